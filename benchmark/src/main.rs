@@ -4,29 +4,69 @@ extern crate scheduler;
 extern crate statrs;
 
 use histogram::Histogram;
-use scheduler::cpupool::{CpuPool, SegregatedCpuPool, WorkStealingCpuPool};
-use scheduler::cycles::{rdtsc, to_seconds};
-use scheduler::dispatcher::RandomDispatcher;
-use scheduler::task::{Task, TaskState};
-use scheduler::waiter::{WaitResult, Waiter};
+use scheduler::cpupool::{CpuPool, DispatchMode, PoolConfig, SegregatedCpuPool, WorkStealingCpuPool};
+use scheduler::cycles::{from_seconds, rdtsc, to_seconds};
+use scheduler::dispatcher::{
+    Dispatcher, JoinIdleQueueDispatcher, PowerOfTwoDispatcher, RandomDispatcher, RoundRobinDispatcher,
+    SampledLoadAwareDispatcher,
+};
+use scheduler::executor::ElephantPolicy;
+use scheduler::steal::{StealPolicyKind, StealStats, STEAL_POLICIES};
+use scheduler::task::{Priority, Task, TaskState};
+use scheduler::trace::chrome_trace;
+use scheduler::waiter::{completions, Lifecycle, WaitResult, Waiter};
+use std::collections::HashMap;
 use std::env;
+use std::fs::File;
+use std::io::Write;
 use scheduler::dispatcher::LoadAwareDispatcher;
 
 mod data;
 mod primes;
 
+// events each executor keeps when tracing; older ones are dropped
+const TRACE_CAPACITY: usize = 1 << 16;
+
+// 'injector' runs the pool without a dispatcher, as long as its injector
+// has room.
+const DISPATCHERS: [&str; 7] = [
+    "load-aware",
+    "random",
+    "round-robin",
+    "power-of-two",
+    "sampled-load-aware",
+    "join-idle-queue",
+    "injector",
+];
+
+fn make_dispatcher(name: &str) -> Box<Dispatcher> {
+    match name {
+        "load-aware" => Box::new(LoadAwareDispatcher::new()),
+        "random" => Box::new(RandomDispatcher::new()),
+        "round-robin" => Box::new(RoundRobinDispatcher::new()),
+        "power-of-two" => Box::new(PowerOfTwoDispatcher::new()),
+        "sampled-load-aware" => Box::new(SampledLoadAwareDispatcher::new(3)),
+        "join-idle-queue" => Box::new(JoinIdleQueueDispatcher::new()),
+        "injector" => Box::new(LoadAwareDispatcher::new()),
+        _ => panic!("Unknown dispatcher {}", name),
+    }
+}
+
 fn warm_up(pool: &CpuPool) {
     let waiters: Vec<(usize, Waiter<WaitResult<usize>>)> = vec![1000 as usize, 1000]
         .into_iter()
         .map(|data| {
             // create a task
             let mut prime_calculation = primes::Primatizer::new(data);
-            let mut task = Task::new(move || {
-                prime_calculation.step(data);
-                (
+            // a time slice may cut the step short
+            let mut task = Task::new(move || match prime_calculation.step(data) {
+                TaskState::Complete => (
                     TaskState::Complete,
                     Some(prime_calculation.get_last_prime()),
-                )
+                ),
+                TaskState::Incomplete => (TaskState::Incomplete, None),
+                TaskState::Error => (TaskState::Error, None),
+                TaskState::Unstarted => (TaskState::Unstarted, None),
             });
             let waiter = task.waiter().unwrap();
             let boxed_task = Box::new(task);
@@ -51,10 +91,27 @@ fn run_benchmark(
     n_threads: usize,
     n_cores: usize,
     n_elephants: usize,
+    time_slice: Option<u64>,
+    elephants: Option<ElephantPolicy>,
+    steal_policy: StealPolicyKind,
+    dispatcher: &str,
+    trace_path: Option<&str>,
+    elephant_priority: Priority,
     task_data: Vec<(u64, usize)>,
-) -> Vec<(u64, usize, f64, usize)> {
-    let mut dispatcher = Box::new(LoadAwareDispatcher::new());
-    let pool = WorkStealingCpuPool::new(n_threads, n_cores, dispatcher);
+) -> (Vec<(u64, usize, f64, usize, Lifecycle)>, usize, usize, usize, StealStats) {
+    let config = PoolConfig {
+        time_slice,
+        elephants,
+        steal_policy,
+        dispatch: if dispatcher == "injector" {
+            DispatchMode::Injector
+        } else {
+            DispatchMode::Targeted
+        },
+        trace_capacity: trace_path.map(|_| TRACE_CAPACITY),
+        ..PoolConfig::default()
+    };
+    let pool = WorkStealingCpuPool::new_with_config(n_threads, n_cores, make_dispatcher(dispatcher), config);
 
     warm_up(&pool);
     let big_task_size = 100_000;
@@ -72,19 +129,19 @@ fn run_benchmark(
                 TaskState::Incomplete => (TaskState::Incomplete, None),
                 TaskState::Error => (TaskState::Error, None),
                 TaskState::Unstarted => (TaskState::Unstarted, None),
-            });
+            })
+            .with_priority(elephant_priority);
             let waiter = task.waiter().unwrap();
-            let sched_result = pool.schedule(Box::new(task));
-            match sched_result {
-                Ok(cpu) => println!("{}", cpu),
-                Err(_) => panic!("Schedule failure"),
+            // stdout is kept for results
+            if pool.schedule(Box::new(task)).is_err() {
+                panic!("Schedule failure");
             }
 
             waiter
         })
         .collect();
 
-    let waiters: Vec<(u64, usize, Waiter<WaitResult<usize>>)> = task_data
+    let (task_sizes, waiters): (Vec<(u64, usize)>, Vec<Waiter<WaitResult<usize>>>) = task_data
         .into_iter()
         .map(|data| {
             // spin for a certain amount of time
@@ -95,19 +152,22 @@ fn run_benchmark(
             while ((to_seconds(rdtsc()) * 1e9) as u64) < until {}
             // create a task
             let mut prime_calculation = primes::Primatizer::new(n);
-            let mut task = Task::new(move || {
-                prime_calculation.step(n);
-                (
+            // a time slice may cut the step short
+            let mut task = Task::new(move || match prime_calculation.step(n) {
+                TaskState::Complete => (
                     TaskState::Complete,
                     Some(prime_calculation.get_last_prime()),
-                )
+                ),
+                TaskState::Incomplete => (TaskState::Incomplete, None),
+                TaskState::Error => (TaskState::Error, None),
+                TaskState::Unstarted => (TaskState::Unstarted, None),
             });
             let waiter = task.waiter().unwrap();
             let boxed_task = Box::new(task);
             pool.schedule(boxed_task);
-            (delay, n, waiter)
+            ((delay, n), waiter)
         })
-        .collect();
+        .unzip();
 
     let b: Vec<f64> = big_tasks
         .into_iter()
@@ -124,20 +184,34 @@ fn run_benchmark(
         })
         .collect();
 
-    waiters
-        .into_iter()
-        .map(|(delay, size, waiter)| {
-            let (result, n_steals) = match waiter.await() {
+    // collect results as they finish so a slow task does not hold up the rest
+    let results = completions(waiters)
+        .map(|(i, wait_result)| {
+            let (delay, size) = task_sizes[i];
+            let (result, n_steals, lifecycle) = match wait_result {
                 Ok(wait_result) => {
                     let cycles = wait_result.get_total_time();
                     let steals = wait_result.get_n_steals();
-                    (to_seconds(cycles), steals)
+                    (to_seconds(cycles), steals, wait_result.get_lifecycle().clone())
                 }
                 Err(_) => panic!("Error waiting for task"),
             };
-            (delay, size, result, n_steals)
+            (delay, size, result, n_steals, lifecycle)
         })
-        .collect()
+        .collect();
+
+    if let Some(path) = trace_path {
+        let mut file = File::create(path).unwrap();
+        file.write_all(chrome_trace(&pool.trace()).as_bytes()).unwrap();
+    }
+
+    (
+        results,
+        pool.count_parks(),
+        pool.count_overruns(),
+        pool.count_reclassified(),
+        pool.steal_stats(),
+    )
 }
 
 fn fixed_size_run(frequency: u64, size: usize, n_tasks: usize, n_threads: usize, n_cores: usize) {
@@ -145,10 +219,22 @@ fn fixed_size_run(frequency: u64, size: usize, n_tasks: usize, n_threads: usize,
     let sizes = vec![size; n_tasks];
     let data: Vec<(u64, usize)> = freq.into_iter().zip(sizes).collect();
 
-    let results = run_benchmark(n_threads, n_cores, 0, data);
+    let steal_policy = PoolConfig::default().steal_policy;
+    let (results, _, _, _, _) = run_benchmark(
+        n_threads,
+        n_cores,
+        0,
+        None,
+        None,
+        steal_policy,
+        "load-aware",
+        None,
+        Priority::Normal,
+        data,
+    );
 
     let mut hist = Histogram::new();
-    results.into_iter().for_each(|(_, _, time, _)| {
+    results.into_iter().for_each(|(_, _, time, _, _)| {
         // println!("{}", time);
         &hist.increment((time * 1e9) as u64);
     });
@@ -167,29 +253,112 @@ fn elephant_run(
     n_threads: usize,
     n_cores: usize,
     n_elephants: usize,
+    time_slice: Option<u64>,
+    elephants: Option<ElephantPolicy>,
+    steal_policy: StealPolicyKind,
+    dispatcher: &str,
+    trace_path: Option<&str>,
+    elephant_priority: Priority,
 ) {
     let freq = vec![frequency; n_tasks];
     let sizes = vec![size; n_tasks];
     let data: Vec<(u64, usize)> = freq.into_iter().zip(sizes).collect();
 
-    let results = run_benchmark(n_threads, n_cores, n_elephants, data);
+    let (results, n_parks, n_overruns, n_reclassified, steal_stats) = run_benchmark(
+        n_threads,
+        n_cores,
+        n_elephants,
+        time_slice,
+        elephants,
+        steal_policy,
+        dispatcher,
+        trace_path,
+        elephant_priority,
+        data,
+    );
 
     let mut hist = Histogram::new();
     let mut total_steals = 0;
-    results.into_iter().for_each(|(_, _, time, n_steals)| {
+    let mut lifecycles = vec![];
+    results.into_iter().for_each(|(_, _, time, n_steals, lifecycle)| {
         // println!("{}", time);
         &hist.increment((time * 1e9) as u64);
         total_steals += n_steals;
+        lifecycles.push(lifecycle);
     });
 
+    // as named in ROW_COLUMNS; new columns only ever go at the end, for
+    // scripts that read them by position
     println!(
-        "{}\t{}\t{}",
+        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.2}\t{}\t{}",
         hist.percentile(50.0).unwrap(),
         hist.percentile(99.0).unwrap(),
-        total_steals
+        total_steals,
+        n_parks,
+        n_overruns,
+        n_reclassified,
+        steal_policy.name(),
+        steal_stats.mean_batch_size(),
+        dispatcher,
+        breakdown_columns(&lifecycles)
     );
 }
 
+// The columns of an elephant_run row, in order.
+const ROW_COLUMNS: [&str; 9] = [
+    "p50_ns",
+    "p99_ns",
+    "steals",
+    "parks",
+    "overruns",
+    "reclassified",
+    "steal_policy",
+    "mean_batch",
+    "dispatcher",
+];
+// Followed by a p50 and a p99 column for each of these.
+const BREAKDOWN_STAGES: [&str; 5] = ["dispatch_ns", "queueing_ns", "service_ns", "result_ns", "migration_count"];
+
+fn row_header() -> String {
+    let breakdown = BREAKDOWN_STAGES
+        .iter()
+        .flat_map(|stage| vec![format!("{}_p50", stage), format!("{}_p99", stage)]);
+    let columns: Vec<String> = ROW_COLUMNS.iter().map(|column| column.to_string()).chain(breakdown).collect();
+    columns.join("\t")
+}
+
+// Where the time between scheduling a task and hearing back from it went,
+// and how many times tasks moved between cpus, as the columns named in
+// BREAKDOWN_STAGES. A stage no task went through shows up as "-".
+fn breakdown_columns(lifecycles: &[Lifecycle]) -> String {
+    let nanos = |cycles: u64| (to_seconds(cycles) * 1e9) as u64;
+    let stages: Vec<Vec<u64>> = vec![
+        lifecycles.iter().filter_map(|l| l.dispatch_delay()).map(nanos).collect(),
+        lifecycles.iter().filter_map(|l| l.queueing_delay()).map(nanos).collect(),
+        lifecycles.iter().map(|l| nanos(l.service_time())).collect(),
+        lifecycles
+            .iter()
+            .map(|l| nanos(l.result_sent.saturating_sub(l.last_tick_end)))
+            .collect(),
+        lifecycles.iter().map(|l| l.migrations() as u64).collect(),
+    ];
+    let columns: Vec<String> = stages
+        .into_iter()
+        .map(|values| {
+            let mut hist = Histogram::new();
+            for value in values {
+                hist.increment(value).unwrap();
+            }
+            if hist.entries() == 0 {
+                String::from("-\t-")
+            } else {
+                format!("{}\t{}", hist.percentile(50.0).unwrap(), hist.percentile(99.0).unwrap())
+            }
+        })
+        .collect();
+    columns.join("\t")
+}
+
 fn exp_gamma_run(
     frequency: data::F64exponentialUncertain,
     size: data::F64gammaUncertain,
@@ -201,10 +370,22 @@ fn exp_gamma_run(
         .into_iter()
         .map(|d: (f64, f64)| (d.0 as u64, d.1 as usize))
         .collect();
-    let results = run_benchmark(n_threads, n_cores, 0, data);
+    let steal_policy = PoolConfig::default().steal_policy;
+    let (results, _, _, _, _) = run_benchmark(
+        n_threads,
+        n_cores,
+        0,
+        None,
+        None,
+        steal_policy,
+        "load-aware",
+        None,
+        Priority::Normal,
+        data,
+    );
 
     let mut hist = Histogram::new();
-    results.into_iter().for_each(|(_, _, time, _)| {
+    results.into_iter().for_each(|(_, _, time, _, _)| {
         &hist.increment((time * 1e9) as u64);
     });
 
@@ -222,11 +403,43 @@ fn print_header() {
     println!("Cores\t# of Tasks\tNth Prime\tTime");
 }
 
+// Splits the command line into positional arguments and `--name value`
+// options.
+fn parse_args(args: &[String]) -> (Vec<&str>, HashMap<&str, &str>) {
+    let mut positional = vec![];
+    let mut options = HashMap::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg.starts_with("--") {
+            let value = args.next().map_or("", |value| value.as_str());
+            options.insert(&arg[2..], value);
+        } else {
+            positional.push(arg.as_str());
+        }
+    }
+    (positional, options)
+}
+
+// `choice` names one of `names`, or is 'all' to sweep every one of them.
+fn parse_choice<'a>(option: &str, choice: &str, names: &[&'a str]) -> Option<Vec<&'a str>> {
+    if choice == "all" {
+        return Some(names.to_vec());
+    }
+    match names.iter().find(|&&name| name == choice) {
+        Some(&name) => Some(vec![name]),
+        None => {
+            println!("Unknown {} {}, expected one of: {}, all", option, choice, names.join(", "));
+            None
+        }
+    }
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 7 {
+    let (positional, options) = parse_args(&args);
+    if positional.len() != 7 {
         println!(
-            "Usage: {} <delay (ns): u64> <nth_prime: usize> <n_tasks: usize> <n_threads: usize> <n_cores: usize> <n_elephants: usize>",
+            "Usage: {} <delay (ns): u64> <nth_prime: usize> <n_tasks: usize> <n_threads: usize> <n_cores: usize> <n_elephants: usize> [--time-slice <us>] [--elephant-ticks <n>] [--steal-policy <name|all>] [--dispatcher <name|all>] [--trace <path>] [--elephant-priority <high|normal|low>]",
             args[0]
         );
         return;
@@ -240,17 +453,76 @@ fn main() {
     //        rate: args[4].parse().unwrap(),
     //        scale: args[5].parse().unwrap(),
     //    };
-    let n_tasks: usize = args[3].parse().unwrap();
-    let n_threads: usize = args[4].parse().unwrap();
-    let n_cores: usize = args[5].parse().unwrap();
-    let n_elephants: usize = args[6].parse().unwrap();
-
-    elephant_run(
-        args[1].parse().unwrap(),
-        args[2].parse().unwrap(),
-        n_tasks,
-        n_threads,
-        n_cores,
-        n_elephants,
-    );
+    let n_tasks: usize = positional[3].parse().unwrap();
+    let n_threads: usize = positional[4].parse().unwrap();
+    let n_cores: usize = positional[5].parse().unwrap();
+    let n_elephants: usize = positional[6].parse().unwrap();
+    let time_slice: Option<u64> = options
+        .get("time-slice")
+        .map(|micros| from_seconds(micros.parse::<f64>().unwrap() * 1e-6));
+    // tasks still going after this many ticks are reclassified as elephants.
+    // Only tasks that get sliced up take more than one tick.
+    let elephants: Option<ElephantPolicy> = options.get("elephant-ticks").map(|ticks| ElephantPolicy {
+        max_ticks: ticks.parse().unwrap(),
+        ..ElephantPolicy::default()
+    });
+
+    let policy_names: Vec<&str> = STEAL_POLICIES.iter().map(|policy| policy.name()).collect();
+    let default_policy = PoolConfig::default().steal_policy.name();
+    let steal_policies = match parse_choice(
+        "steal policy",
+        options.get("steal-policy").unwrap_or(&default_policy),
+        &policy_names,
+    ) {
+        Some(names) => names,
+        None => return,
+    };
+    let dispatchers = match parse_choice(
+        "dispatcher",
+        options.get("dispatcher").unwrap_or(&DISPATCHERS[0]),
+        &DISPATCHERS,
+    ) {
+        Some(names) => names,
+        None => return,
+    };
+
+    // elephants compete with mice on equal terms unless asked otherwise
+    let elephant_priority = match options.get("elephant-priority").map(|name| *name) {
+        None | Some("normal") => Priority::Normal,
+        Some("high") => Priority::High,
+        Some("low") => Priority::Low,
+        Some(name) => {
+            println!("Unknown elephant priority {}, expected one of: high, normal, low", name);
+            return;
+        }
+    };
+
+    println!("{}", row_header());
+    let sweep = dispatchers.len() * steal_policies.len() > 1;
+    for dispatcher in &dispatchers {
+        for steal_policy in &steal_policies {
+            // every run of a sweep gets a trace of its own
+            let trace_path = options.get("trace").map(|path| {
+                if sweep {
+                    format!("{}.{}.{}.json", path, dispatcher, steal_policy)
+                } else {
+                    path.to_string()
+                }
+            });
+            elephant_run(
+                positional[1].parse().unwrap(),
+                positional[2].parse().unwrap(),
+                n_tasks,
+                n_threads,
+                n_cores,
+                n_elephants,
+                time_slice,
+                elephants.clone(),
+                steal_policy.parse().unwrap(),
+                dispatcher,
+                trace_path.as_ref().map(|path| path.as_str()),
+                elephant_priority,
+            );
+        }
+    }
 }
@@ -1,5 +1,4 @@
-use scheduler::cycles::{rdtsc, to_seconds};
-use scheduler::task::TaskState;
+use scheduler::task::{should_yield, TaskState};
 use std::cmp::min;
 
 pub struct Primatizer {
@@ -17,9 +16,14 @@ impl Primatizer {
         }
     }
 
+    // Takes up to n_steps steps, but gives up early once the executor's time
+    // slice is used up, so the executor gets its turn back right away.
     pub fn step(&mut self, n_steps: usize) -> TaskState {
         let until = min(self.nth_prime, self.counter + n_steps);
         while self.counter < until {
+            if should_yield() {
+                return TaskState::Incomplete;
+            }
             self.last_prime = next_prime(self.last_prime);
             self.counter += 1;
         }
@@ -27,7 +31,6 @@ impl Primatizer {
         if self.counter == self.nth_prime {
             TaskState::Complete
         } else {
-            // Primatizer::preempt()
             TaskState::Incomplete
         }
     }
@@ -36,13 +39,6 @@ impl Primatizer {
         self.last_prime
     }
 
-    fn preempt() -> TaskState {
-        let now = (to_seconds(rdtsc()) * 1e9) as u64;
-        let until = now + 250_000; // 250 microseconds to simulate cost of preemption
-        while ((to_seconds(rdtsc()) * 1e9) as u64) < until {}
-
-        TaskState::Incomplete
-    }
 }
 
 fn is_prime(n: usize) -> bool {
@@ -90,6 +86,31 @@ fn nth_prime_test() {
     assert_eq!(8161, c.get_last_prime())
 }
 
+#[test]
+fn step_yields_test() {
+    use scheduler::cpupool::{CpuPool, PoolConfig, WorkStealingCpuPool};
+    use scheduler::cycles::from_seconds;
+    use scheduler::dispatcher::RandomDispatcher;
+    use scheduler::task::Task;
+
+    let config = PoolConfig {
+        time_slice: Some(from_seconds(0.0001)),
+        ..PoolConfig::default()
+    };
+    let pool = WorkStealingCpuPool::new_with_config(1, 1, Box::new(RandomDispatcher::new()), config);
+    // asks for every step at once, but only gets a slice's worth per tick
+    let mut primes = Primatizer::new(5000);
+    let mut task = Task::new(move || match primes.step(5000) {
+        TaskState::Complete => (TaskState::Complete, Some(primes.get_last_prime())),
+        state => (state, None),
+    });
+    let waiter = task.waiter().unwrap();
+    pool.schedule(Box::new(task)).unwrap();
+    let result = waiter.await().unwrap();
+    assert!(result.get_ticks() > 1);
+    assert_eq!(48611, result.into_result());
+}
+
 #[test]
 fn is_prime_test() {
     assert_eq!(false, is_prime(15));
@@ -1,90 +1,1002 @@
 use super::dispatcher::{Dispatcher, LoadAwareDispatcher};
-use super::executor::Executor;
-use super::task::Iterable;
-use crossbeam_deque::Stealer;
+use super::events::{SchedulerEvents, SilentEvents};
+use super::executor::{ElephantPolicy, Executor, IdleStrategy, ShutdownMode};
+use super::forkjoin::{scope_on, Scope};
+use super::injector::Injector;
+use super::queue::{QueuePolicy, WorkStealer};
+use super::stats::PoolStats;
+use super::steal::{StealPolicyKind, StealStats, Victim};
+use super::task::{AbortReason, Iterable};
+use super::trace::ExecutorTrace;
+use std::cmp::min;
+use std::mem;
+use std::sync::atomic::{fence, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::usize;
 
-pub trait CpuPool {
+// What schedule returns for tasks that went to the pool's injector, since
+// there is no telling which cpu will pick them up.
+pub const ANY_CPU: usize = usize::MAX;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DispatchMode {
+    // the dispatcher picks an executor for every task
+    Targeted,
+    // tasks go onto a queue shared by the whole pool, which idle executors
+    // pull from, and the dispatcher is only asked once that queue is full
+    Injector,
+}
+
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    pub idle: IdleStrategy,
+    // the order each executor ticks its local work in
+    pub queue_policy: QueuePolicy,
+    // how many times in a row an executor may pass over a priority level
+    // with work waiting before it serves that level anyway
+    pub aging_threshold: usize,
+    // how many cycles a tick may take before the task ought to yield, for
+    // tasks that do not bring a budget of their own. See task::should_yield.
+    // A tick that takes longer than its slice counts as an overrun.
+    pub time_slice: Option<u64>,
+    // cycles a tick may run past its slice before it counts as an overrun. A
+    // task only notices its slice is up once it is, so even a tick that
+    // yields right away goes a little over.
+    pub overrun_tolerance: u64,
+    // when set, tasks that run for too long are moved out of the way of
+    // short ones
+    pub elephants: Option<ElephantPolicy>,
+    // how idle executors pick who to steal from
+    pub steal_policy: StealPolicyKind,
+    // the most tasks a single steal may take, however many the policy asks
+    // for
+    pub max_steal_batch: usize,
+    pub dispatch: DispatchMode,
+    // how many tasks the injector holds, rounded up to a power of two
+    pub injector_capacity: usize,
+    // how long dropping a pool waits for queued work to drain before it
+    // starts cancelling whatever is left.
+    pub drain_timeout: Duration,
+    // when set, every executor keeps its last this many trace events. See
+    // CpuPool::trace.
+    pub trace_capacity: Option<usize>,
+    // where executors report what goes wrong along the way
+    pub events: Arc<SchedulerEvents>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> PoolConfig {
+        PoolConfig {
+            idle: IdleStrategy::default(),
+            queue_policy: QueuePolicy::Priority,
+            aging_threshold: 64,
+            time_slice: None,
+            overrun_tolerance: 0,
+            elephants: None,
+            steal_policy: StealPolicyKind::LongestQueue,
+            max_steal_batch: 32,
+            dispatch: DispatchMode::Targeted,
+            injector_capacity: 4096,
+            drain_timeout: Duration::from_millis(1000),
+            trace_capacity: None,
+            events: Arc::new(SilentEvents),
+        }
+    }
+}
+
+// Pools can be shared between threads, e.g. in an Arc, and scheduled into
+// from all of them at once. Executors hand out work through mpsc Senders,
+// which are only Sync from Rust 1.72 on; see rust-version in Cargo.toml.
+pub trait CpuPool: Send + Sync {
     fn schedule(&self, task: Box<Iterable>) -> Result<usize, ()>;
+    fn shutdown(&mut self, mode: ShutdownMode);
+    // number of times any executor in the pool went to sleep for lack of work
+    fn count_parks(&self) -> usize;
+    // number of task panics caught by the pool's executors
+    fn count_panics(&self) -> usize;
+    // number of ticks that ran past their time slice
+    fn count_overruns(&self) -> usize;
+    // number of tasks reclassified as elephants
+    fn count_reclassified(&self) -> usize;
+    // how many steals the pool's executors made, and how much they took
+    fn steal_stats(&self) -> StealStats;
+    // everything the executors have counted, executor by executor
+    fn stats(&self) -> PoolStats;
+    // the events each executor traced, if tracing is on; see
+    // trace::chrome_trace
+    fn trace(&self) -> Vec<ExecutorTrace>;
 }
 
 pub struct WorkStealingCpuPool {
     dispatcher: Box<Dispatcher>,
+    // executors set aside for elephants, which the dispatcher never sees
+    elephants: Vec<Executor>,
+    injector: Option<Arc<Injector<Box<Iterable>>>>,
+    drain_timeout: Duration,
 }
 
 impl WorkStealingCpuPool {
-    pub fn new(n_threads: usize, n_cores: usize, mut dispatcher: Box<Dispatcher>) -> WorkStealingCpuPool {
+    pub fn new(n_threads: usize, n_cores: usize, dispatcher: Box<Dispatcher>) -> WorkStealingCpuPool {
+        WorkStealingCpuPool::new_with_config(n_threads, n_cores, dispatcher, PoolConfig::default())
+    }
+
+    pub fn new_with_config(
+        n_threads: usize,
+        n_cores: usize,
+        dispatcher: Box<Dispatcher>,
+        config: PoolConfig,
+    ) -> WorkStealingCpuPool {
         let mut cpu_list: Vec<usize> = Vec::with_capacity(n_threads);
         for i in 0..n_threads {
             cpu_list.push(i % n_cores);
         }
-        WorkStealingCpuPool::new_from_list(cpu_list, dispatcher)
+        WorkStealingCpuPool::new_from_list_with_config(cpu_list, dispatcher, config)
+    }
+
+    pub fn new_from_list(cpu_thread_list: Vec<usize>, dispatcher: Box<Dispatcher>) -> WorkStealingCpuPool {
+        WorkStealingCpuPool::new_from_list_with_config(cpu_thread_list, dispatcher, PoolConfig::default())
     }
 
-    pub fn new_from_list(cpu_thread_list: Vec<usize>, mut dispatcher: Box<Dispatcher>) -> WorkStealingCpuPool {
+    pub fn new_from_list_with_config(
+        cpu_thread_list: Vec<usize>,
+        mut dispatcher: Box<Dispatcher>,
+        config: PoolConfig,
+    ) -> WorkStealingCpuPool {
         let n_threads = cpu_thread_list.len();
-        let workers: Vec<(Executor, Stealer<Box<Iterable>>)> = cpu_thread_list
-            .into_iter()
-            .map(|cpu_thread_id: usize| Executor::new(cpu_thread_id, n_threads - 1))
+        // the last few threads go to the elephants, but never all of them
+        let n_elephant_cores = config
+            .elephants
+            .as_ref()
+            .map_or(0, |policy| min(policy.elephant_cores, n_threads.saturating_sub(1)));
+        let n_mice = n_threads - n_elephant_cores;
+        let injector = new_injector(&config);
+
+        let elephants: Vec<(Executor, WorkStealer)> = cpu_thread_list[n_mice..]
+            .iter()
+            .map(|&cpu_thread_id| Executor::new(cpu_thread_id, n_threads - 1, &config, vec![], None))
+            .collect();
+        let lanes: Vec<_> = elephants.iter().map(|&(ref elephant, _)| elephant.elephant_lane()).collect();
+        let workers: Vec<(Executor, WorkStealer)> = cpu_thread_list[..n_mice]
+            .iter()
+            .map(|&cpu_thread_id| {
+                Executor::new(cpu_thread_id, n_mice - 1, &config, lanes.clone(), injector.clone())
+            })
             .collect();
 
-        // inject stealers
-        for (executor_a, _) in &workers {
-            &workers
-                .iter()
-                .filter(|&&(ref worker, _)| worker as *const _ != executor_a as *const _)
-                .for_each(|&(_, ref stealer)| {
-                    executor_a.send_stealer(stealer.clone()).unwrap();
-                });
-        }
+        // inject stealers: elephant cores may help anybody out, but the rest
+        // never steal elephants back.
+        inject_stealers(&workers, &workers);
+        inject_stealers(&elephants, &workers);
+        inject_stealers(&elephants, &elephants);
 
         let workers_for_dispatch: Vec<Executor> =
             workers.into_iter().map(|(worker, _)| worker).collect();
         dispatcher.inject_fleet(workers_for_dispatch);
         WorkStealingCpuPool {
             dispatcher,
+            elephants: elephants.into_iter().map(|(elephant, _)| elephant).collect(),
+            injector,
+            drain_timeout: config.drain_timeout,
         }
     }
+
+    // Fork-join over the pool: tasks spawned on `scope` may borrow from the
+    // caller, and this only returns once all of them have completed.
+    pub fn scope<'scope, F, T>(&'scope self, op: F) -> T
+    where
+        F: FnOnce(&Scope<'scope>) -> T,
+    {
+        scope_on(self, op)
+    }
 }
 
 impl CpuPool for WorkStealingCpuPool {
     fn schedule(&self, task: Box<Iterable>) -> Result<usize, ()> {
-        match self.dispatcher.select() {
-            Some(executor) => {
-                executor.schedule(task);
-                Ok(executor.get_cpu())
-            }
-            None => Err(()),
+        schedule_on(&self.dispatcher, &self.injector, task)
+    }
+
+    // the elephant cores go last, since the others may still be handing
+    // them work until they are done.
+    fn shutdown(&mut self, mode: ShutdownMode) {
+        shutdown_fleet(self.dispatcher.flush(), mode);
+        shutdown_fleet(mem::replace(&mut self.elephants, vec![]), mode);
+    }
+
+    fn count_parks(&self) -> usize {
+        count_fleet_parks(self.dispatcher.fleet()) + count_fleet_parks(&self.elephants)
+    }
+
+    fn count_panics(&self) -> usize {
+        count_fleet_panics(self.dispatcher.fleet()) + count_fleet_panics(&self.elephants)
+    }
+
+    fn count_overruns(&self) -> usize {
+        count_fleet_overruns(self.dispatcher.fleet()) + count_fleet_overruns(&self.elephants)
+    }
+
+    fn count_reclassified(&self) -> usize {
+        count_fleet_reclassified(self.dispatcher.fleet()) + count_fleet_reclassified(&self.elephants)
+    }
+
+    fn steal_stats(&self) -> StealStats {
+        fleet_steal_stats(self.dispatcher.fleet()).merge(fleet_steal_stats(&self.elephants))
+    }
+
+    // the elephant cores come last
+    fn stats(&self) -> PoolStats {
+        PoolStats {
+            executors: self
+                .dispatcher
+                .fleet()
+                .iter()
+                .chain(self.elephants.iter())
+                .map(|executor| executor.stats())
+                .collect(),
         }
     }
+
+    fn trace(&self) -> Vec<ExecutorTrace> {
+        self.dispatcher
+            .fleet()
+            .iter()
+            .chain(self.elephants.iter())
+            .map(|executor| executor.trace())
+            .collect()
+    }
+}
+
+impl Drop for WorkStealingCpuPool {
+    fn drop(&mut self) {
+        drain_fleet(self.dispatcher.flush(), self.drain_timeout);
+        drain_fleet(mem::replace(&mut self.elephants, vec![]), self.drain_timeout);
+    }
 }
 
 pub struct SegregatedCpuPool {
     dispatcher: Box<Dispatcher>,
+    injector: Option<Arc<Injector<Box<Iterable>>>>,
+    drain_timeout: Duration,
 }
 
 impl SegregatedCpuPool {
-    pub fn new(n_threads: usize, mut dispatcher: Box<Dispatcher>) -> SegregatedCpuPool {
+    pub fn new(n_threads: usize, dispatcher: Box<Dispatcher>) -> SegregatedCpuPool {
+        SegregatedCpuPool::new_with_config(n_threads, dispatcher, PoolConfig::default())
+    }
+
+    pub fn new_with_config(
+        n_threads: usize,
+        mut dispatcher: Box<Dispatcher>,
+        config: PoolConfig,
+    ) -> SegregatedCpuPool {
+        let injector = new_injector(&config);
         let mut workers = Vec::with_capacity(n_threads);
         for i in 0..n_threads {
-            let (executor, _) = Executor::new(i, 0);
+            let (executor, _) = Executor::new(i, 0, &config, vec![], injector.clone());
             workers.push(executor);
         }
         dispatcher.inject_fleet(workers);
         SegregatedCpuPool {
             dispatcher,
+            injector,
+            drain_timeout: config.drain_timeout,
         }
     }
 }
 
 impl CpuPool for SegregatedCpuPool {
     fn schedule(&self, task: Box<Iterable>) -> Result<usize, ()> {
-        match self.dispatcher.select() {
-            Some(executor) => {
-                executor.schedule(task);
-                Ok(executor.get_cpu())
+        schedule_on(&self.dispatcher, &self.injector, task)
+    }
+
+    fn shutdown(&mut self, mode: ShutdownMode) {
+        shutdown_fleet(self.dispatcher.flush(), mode);
+    }
+
+    fn count_parks(&self) -> usize {
+        count_fleet_parks(self.dispatcher.fleet())
+    }
+
+    fn count_panics(&self) -> usize {
+        count_fleet_panics(self.dispatcher.fleet())
+    }
+
+    fn count_overruns(&self) -> usize {
+        count_fleet_overruns(self.dispatcher.fleet())
+    }
+
+    fn count_reclassified(&self) -> usize {
+        count_fleet_reclassified(self.dispatcher.fleet())
+    }
+
+    // executors here never steal, so there is nothing to show
+    fn steal_stats(&self) -> StealStats {
+        fleet_steal_stats(self.dispatcher.fleet())
+    }
+
+    fn stats(&self) -> PoolStats {
+        PoolStats {
+            executors: self.dispatcher.fleet().iter().map(|executor| executor.stats()).collect(),
+        }
+    }
+
+    fn trace(&self) -> Vec<ExecutorTrace> {
+        self.dispatcher.fleet().iter().map(|executor| executor.trace()).collect()
+    }
+}
+
+impl Drop for SegregatedCpuPool {
+    fn drop(&mut self) {
+        drain_fleet(self.dispatcher.flush(), self.drain_timeout);
+    }
+}
+
+fn new_injector(config: &PoolConfig) -> Option<Arc<Injector<Box<Iterable>>>> {
+    match config.dispatch {
+        DispatchMode::Targeted => None,
+        DispatchMode::Injector => Some(Arc::new(Injector::new(config.injector_capacity))),
+    }
+}
+
+fn schedule_on(
+    dispatcher: &Box<Dispatcher>,
+    injector: &Option<Arc<Injector<Box<Iterable>>>>,
+    mut task: Box<Iterable>,
+) -> Result<usize, ()> {
+    let fleet = dispatcher.fleet();
+    // nobody left to pick the task up after a shutdown
+    if fleet.is_empty() {
+        return Err(());
+    }
+    task.mark_dispatched();
+    let task = match *injector {
+        Some(ref injector) => match injector.push(task) {
+            Ok(()) => {
+                // busy executors will get to it, but a parked one would not.
+                // Pairs with the fence in InnerExecutor::park: either the
+                // executor sees the task, or we see its flag.
+                fence(Ordering::SeqCst);
+                if let Some(executor) = fleet.iter().find(|executor| executor.is_parked()) {
+                    executor.unpark();
+                }
+                return Ok(ANY_CPU);
             }
-            None => Err(()),
+            Err(task) => task,
+        },
+        None => task,
+    };
+
+    match dispatcher.select() {
+        Some(executor) => match executor.schedule(task) {
+            Ok(_) => Ok(executor.get_cpu()),
+            Err(_) => Err(()),
+        },
+        None => Err(()),
+    }
+}
+
+fn count_fleet_parks(fleet: &[Executor]) -> usize {
+    fleet.iter().map(|executor| executor.count_parks()).sum()
+}
+
+fn count_fleet_panics(fleet: &[Executor]) -> usize {
+    fleet.iter().map(|executor| executor.count_panics()).sum()
+}
+
+fn count_fleet_overruns(fleet: &[Executor]) -> usize {
+    fleet.iter().map(|executor| executor.count_overruns()).sum()
+}
+
+fn count_fleet_reclassified(fleet: &[Executor]) -> usize {
+    fleet.iter().map(|executor| executor.count_reclassified()).sum()
+}
+
+fn fleet_steal_stats(fleet: &[Executor]) -> StealStats {
+    fleet
+        .iter()
+        .map(|executor| executor.steal_stats())
+        .fold(StealStats::default(), StealStats::merge)
+}
+
+// Lets every one of `thieves` steal from every one of `victims` but itself.
+fn inject_stealers(thieves: &[(Executor, WorkStealer)], victims: &[(Executor, WorkStealer)]) {
+    for &(ref thief, _) in thieves {
+        for &(ref victim, ref stealer) in victims {
+            if thief as *const _ != victim as *const _ {
+                thief
+                    .send_stealer(Victim::new(victim.get_cpu(), stealer.clone()), victim.unparker())
+                    .unwrap();
+            }
+        }
+    }
+}
+
+fn shutdown_fleet(mut fleet: Vec<Executor>, mode: ShutdownMode) {
+    // signal everybody first so the executors wind down in parallel
+    for executor in &fleet {
+        executor.shutdown(mode);
+    }
+    for executor in &mut fleet {
+        let _ = executor.join();
+    }
+}
+
+fn drain_fleet(mut fleet: Vec<Executor>, timeout: Duration) {
+    for executor in &fleet {
+        executor.shutdown(ShutdownMode::Drain);
+    }
+
+    let deadline = Instant::now() + timeout;
+    for executor in &fleet {
+        let now = Instant::now();
+        let remaining = if deadline > now {
+            deadline - now
+        } else {
+            Duration::from_millis(0)
+        };
+        if !executor.wait_exit(remaining) {
+            executor.abort(AbortReason::TimedOut);
+        }
+    }
+
+    for executor in &mut fleet {
+        let _ = executor.join();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cycles::{from_seconds, rdtsc};
+    use dispatcher::{
+        DeadlineAwareDispatcher, JoinIdleQueueDispatcher, PowerOfTwoDispatcher, RandomDispatcher,
+        RoundRobinDispatcher, SampledLoadAwareDispatcher,
+    };
+    use events::SchedulerEvent;
+    use executor::current_cpu;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Mutex;
+    use std::task::{Context, Poll, Waker};
+    use std::thread;
+    use task::{should_yield, spawn, Task, TaskState};
+    use trace::TraceEvent;
+    use waiter::{TaskError, ThreadWaker};
+
+    struct Countdown(usize);
+
+    impl Future for Countdown {
+        type Output = usize;
+
+        fn poll(mut self: Pin<&mut Self>, _context: &mut Context) -> Poll<usize> {
+            if self.0 == 0 {
+                Poll::Ready(42)
+            } else {
+                self.0 -= 1;
+                Poll::Pending
+            }
+        }
+    }
+
+    #[test]
+    fn test_shutdown_drain() {
+        let mut pool = WorkStealingCpuPool::new(2, 1, Box::new(RandomDispatcher::new()));
+        let waiters: Vec<_> = (0..8)
+            .map(|i| {
+                let mut steps = 0;
+                let mut task = Task::new(move || {
+                    steps += 1;
+                    if steps < 10 {
+                        (TaskState::Incomplete, None)
+                    } else {
+                        (TaskState::Complete, Some(i))
+                    }
+                });
+                let waiter = task.waiter().unwrap();
+                pool.schedule(Box::new(task)).unwrap();
+                waiter
+            })
+            .collect();
+
+        pool.shutdown(ShutdownMode::Drain);
+        for waiter in waiters {
+            assert_eq!(10, waiter.await().unwrap().get_ticks());
+        }
+        assert!(pool.schedule(Box::new(Task::new(|| (TaskState::Complete, Some(0))))).is_err());
+    }
+
+    #[test]
+    fn test_idle_executors_park() {
+        let mut config = PoolConfig::default();
+        config.idle.spin_iterations = 0;
+        config.idle.yield_iterations = 0;
+        let pool =
+            WorkStealingCpuPool::new_with_config(2, 1, Box::new(RandomDispatcher::new()), config);
+        wait_until(&|| pool.count_parks() > 0);
+        assert!(pool.count_parks() > 0);
+
+        // a parked executor still picks up new work
+        let mut task = Task::new(|| (TaskState::Complete, Some(1)));
+        let waiter = task.waiter().unwrap();
+        pool.schedule(Box::new(task)).unwrap();
+        assert_eq!(1, waiter.await().unwrap().get_ticks());
+    }
+
+    #[test]
+    fn test_panicking_task() {
+        let pool = SegregatedCpuPool::new(1, Box::new(RandomDispatcher::new()));
+        let mut bad_task = Task::new(|| -> (TaskState, Option<usize>) { panic!("bad tick") });
+        let bad_waiter = bad_task.waiter().unwrap();
+        pool.schedule(Box::new(bad_task)).unwrap();
+        match bad_waiter.await() {
+            Err(TaskError::Panicked(payload)) => {
+                assert_eq!(Some(&"bad tick"), payload.downcast_ref::<&str>());
+            }
+            _ => panic!("expected the task to panic"),
+        }
+
+        // the executor survives and keeps serving tasks
+        let mut good_task = Task::new(|| (TaskState::Complete, Some(1)));
+        let good_waiter = good_task.waiter().unwrap();
+        pool.schedule(Box::new(good_task)).unwrap();
+        assert!(good_waiter.await().is_ok());
+        assert_eq!(1, pool.count_panics());
+    }
+
+    #[test]
+    fn test_failing_task() {
+        let pool = SegregatedCpuPool::new(1, Box::new(RandomDispatcher::new()));
+        let mut task = Task::new_fallible(|| (TaskState::Error, Some(Err::<usize, _>("no primes"))));
+        let waiter = task.waiter().unwrap();
+        pool.schedule(Box::new(task)).unwrap();
+        match waiter.await() {
+            Err(TaskError::User(error)) => assert_eq!("no primes", error),
+            _ => panic!("expected the task to fail"),
+        }
+    }
+
+    fn sum_on(pool: &CpuPool) -> usize {
+        let waiters: Vec<_> = (0..16)
+            .map(|i| {
+                let mut task = Task::new(move || (TaskState::Complete, Some(i)));
+                let waiter = task.waiter().unwrap();
+                pool.schedule(Box::new(task)).unwrap();
+                waiter
+            })
+            .collect();
+        waiters.into_iter().map(|waiter| waiter.await().unwrap().into_result()).sum()
+    }
+
+    // for whatever the executors write down after a result is already out;
+    // gives up after a few seconds and leaves it to the asserts to complain
+    fn wait_until(done: &Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() && Instant::now() < deadline {
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn test_dispatchers() {
+        let dispatchers: Vec<fn() -> Box<Dispatcher>> = vec![
+            || Box::new(RoundRobinDispatcher::new()),
+            || Box::new(PowerOfTwoDispatcher::new()),
+            || Box::new(SampledLoadAwareDispatcher::new(2)),
+            || Box::new(JoinIdleQueueDispatcher::new()),
+        ];
+        for dispatcher in dispatchers {
+            assert_eq!(120, sum_on(&WorkStealingCpuPool::new(3, 1, dispatcher())));
+            assert_eq!(120, sum_on(&SegregatedCpuPool::new(3, dispatcher())));
+        }
+
+        let pool = SegregatedCpuPool::new(3, Box::new(RoundRobinDispatcher::new()));
+        let cpus: Vec<usize> = (0..4)
+            .map(|_| pool.schedule(Box::new(Task::new(|| (TaskState::Complete, Some(0))))).unwrap())
+            .collect();
+        assert_eq!(vec![0, 1, 2, 0], cpus);
+    }
+
+    #[test]
+    fn test_many_producers() {
+        let pool = Arc::new(WorkStealingCpuPool::new(2, 1, Box::new(LoadAwareDispatcher::new())));
+        let producers: Vec<_> = (0..4)
+            .map(|_| {
+                let pool = pool.clone();
+                thread::spawn(move || sum_on(&*pool))
+            })
+            .collect();
+        let total: usize = producers.into_iter().map(|producer| producer.join().unwrap()).sum();
+        assert_eq!(4 * 120, total);
+    }
+
+    #[test]
+    fn test_injector() {
+        // small enough that some tasks go through the dispatcher instead
+        let config = PoolConfig {
+            dispatch: DispatchMode::Injector,
+            injector_capacity: 4,
+            ..PoolConfig::default()
+        };
+        let pool = WorkStealingCpuPool::new_with_config(2, 1, Box::new(RandomDispatcher::new()), config.clone());
+        assert_eq!(120, sum_on(&pool));
+        let pool = SegregatedCpuPool::new_with_config(2, Box::new(RandomDispatcher::new()), config);
+        assert_eq!(120, sum_on(&pool));
+
+        let mut task = Task::new(|| (TaskState::Complete, Some(1)));
+        let waiter = task.waiter().unwrap();
+        assert_eq!(Ok(ANY_CPU), pool.schedule(Box::new(task)));
+        assert_eq!(1, waiter.await().unwrap().into_result());
+    }
+
+    #[test]
+    fn test_join_idle_queue() {
+        let pool = SegregatedCpuPool::new(2, Box::new(JoinIdleQueueDispatcher::new()));
+        // an executor only parks once it has told the dispatcher it is idle
+        wait_until(&|| pool.stats().executors.iter().all(|executor| executor.parks > 0));
+        let release = Arc::new(AtomicBool::new(false));
+        let cpus: Vec<usize> = (0..2)
+            .map(|_| {
+                let release = release.clone();
+                let task = Task::new(move || {
+                    if release.load(Ordering::SeqCst) {
+                        (TaskState::Complete, Some(0))
+                    } else {
+                        (TaskState::Incomplete, None)
+                    }
+                });
+                pool.schedule(Box::new(task)).unwrap()
+            })
+            .collect();
+        release.store(true, Ordering::SeqCst);
+        // the first executor is busy by the time the second task comes along
+        assert!(cpus[0] != cpus[1]);
+    }
+
+    #[test]
+    fn test_pool_stats() {
+        let pool = WorkStealingCpuPool::new(2, 1, Box::new(RandomDispatcher::new()));
+        assert_eq!(120, sum_on(&pool));
+        let mut failing = Task::new_fallible(|| (TaskState::Error, Some(Err::<usize, _>("no"))));
+        let mut panicking = Task::new(|| -> (TaskState, Option<usize>) { panic!("bad tick") });
+        let failing_waiter = failing.waiter().unwrap();
+        let panicking_waiter = panicking.waiter().unwrap();
+        pool.schedule(Box::new(failing)).unwrap();
+        pool.schedule(Box::new(panicking)).unwrap();
+        assert!(failing_waiter.await().is_err());
+        assert!(panicking_waiter.await().is_err());
+
+        // the executors may still be wrapping up the last task
+        wait_until(&|| {
+            let total = pool.stats().total();
+            total.tasks_completed + total.errors + total.panics == 18 && total.idle_cycles > 0
+        });
+        let stats = pool.stats();
+        assert_eq!(2, stats.executors.len());
+        let total = stats.total();
+        assert_eq!(16, total.tasks_completed);
+        assert_eq!(1, total.errors);
+        assert_eq!(1, total.panics);
+        assert_eq!(18, total.ticks);
+        assert!(total.busy_cycles > 0 && total.idle_cycles > 0);
+        assert!(total.queue_high_water > 0);
+        // idle executors never stop looking for something to steal
+        assert!(total.steals.attempts > 0);
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        let pool = WorkStealingCpuPool::new(1, 1, Box::new(RandomDispatcher::new()));
+        let mut task = Task::new(|| (TaskState::Complete, Some(0)));
+        let waiter = task.waiter().unwrap();
+        pool.schedule(Box::new(task)).unwrap();
+        let result = waiter.await().unwrap();
+        let lifecycle = result.get_lifecycle();
+        let dispatched = lifecycle.dispatched.unwrap();
+        let enqueued = lifecycle.enqueued.unwrap();
+        assert!(lifecycle.created <= dispatched);
+        assert!(dispatched <= enqueued);
+        assert!(enqueued <= lifecycle.first_tick_start);
+        assert!(lifecycle.first_tick_start <= lifecycle.last_tick_end);
+        assert!(lifecycle.last_tick_end <= lifecycle.result_sent);
+        assert_eq!(Some(enqueued - dispatched), lifecycle.dispatch_delay());
+        assert_eq!(vec![0], lifecycle.cpus);
+        assert_eq!(0, lifecycle.migrations());
+    }
+
+    struct RecordedEvents(Mutex<Vec<SchedulerEvent>>);
+
+    impl SchedulerEvents for RecordedEvents {
+        fn event(&self, _cpu: usize, event: SchedulerEvent) {
+            self.0.lock().unwrap().push(event);
+        }
+    }
+
+    #[test]
+    fn test_scheduler_events() {
+        let events = Arc::new(RecordedEvents(Mutex::new(vec![])));
+        let config = PoolConfig {
+            events: events.clone(),
+            ..PoolConfig::default()
+        };
+        let pool = WorkStealingCpuPool::new_with_config(1, 1, Box::new(RandomDispatcher::new()), config);
+        // nobody asked for this one's result
+        pool.schedule(Box::new(Task::new(|| (TaskState::Complete, Some(0))))).unwrap();
+        let mut failing = Task::new_fallible(|| (TaskState::Error, Some(Err::<usize, _>("no"))));
+        let mut panicking = Task::new(|| -> (TaskState, Option<usize>) { panic!("bad tick") });
+        let failing_waiter = failing.waiter().unwrap();
+        let panicking_waiter = panicking.waiter().unwrap();
+        pool.schedule(Box::new(failing)).unwrap();
+        pool.schedule(Box::new(panicking)).unwrap();
+        assert!(failing_waiter.await().is_err());
+        assert!(panicking_waiter.await().is_err());
+        // the executor may not have got to the first task yet
+        wait_until(&|| events.0.lock().unwrap().len() == 3);
+
+        let mut seen = events.0.lock().unwrap().clone();
+        seen.sort_by_key(|event| format!("{:?}", event));
+        assert_eq!(
+            vec![
+                SchedulerEvent::MissingResultChannel,
+                SchedulerEvent::TaskFailed { panicked: false },
+                SchedulerEvent::TaskFailed { panicked: true },
+            ],
+            seen
+        );
+    }
+
+    #[test]
+    fn test_trace() {
+        let untraced = WorkStealingCpuPool::new(1, 1, Box::new(RandomDispatcher::new()));
+        assert_eq!(120, sum_on(&untraced));
+        assert!(untraced.trace()[0].events.is_empty());
+
+        let config = PoolConfig {
+            trace_capacity: Some(1024),
+            ..PoolConfig::default()
+        };
+        let pool = WorkStealingCpuPool::new_with_config(2, 1, Box::new(RandomDispatcher::new()), config);
+        assert_eq!(120, sum_on(&pool));
+        // executors write all of these down before sending the result
+        let events: Vec<TraceEvent> = pool
+            .trace()
+            .iter()
+            .flat_map(|trace| trace.events.iter().map(|record| record.event))
+            .collect();
+        let count = |matches: &Fn(&TraceEvent) -> bool| events.iter().filter(|event| matches(event)).count();
+        assert_eq!(16, count(&|event| if let TraceEvent::Completed { .. } = event { true } else { false }));
+        assert_eq!(16, count(&|event| if let TraceEvent::TickStart { .. } = event { true } else { false }));
+        assert_eq!(16, count(&|event| if let TraceEvent::TickEnd { .. } = event { true } else { false }));
+        // every task arrives either from the dispatcher or by a steal
+        let arrivals = count(&|event| match event {
+            TraceEvent::Enqueued { .. } | TraceEvent::Stolen { .. } => true,
+            _ => false,
+        });
+        assert!(arrivals >= 16);
+    }
+
+    #[test]
+    fn test_missed_deadline() {
+        let config = PoolConfig {
+            queue_policy: QueuePolicy::EarliestDeadlineFirst,
+            ..PoolConfig::default()
+        };
+        let pool = WorkStealingCpuPool::new_with_config(2, 1, Box::new(DeadlineAwareDispatcher::new()), config);
+        let mut late = Task::new(|| (TaskState::Complete, Some(1))).with_deadline(0);
+        let mut relaxed = Task::new(|| (TaskState::Complete, Some(2)))
+            .with_deadline_at(Instant::now() + Duration::from_secs(60));
+        let late_waiter = late.waiter().unwrap();
+        let relaxed_waiter = relaxed.waiter().unwrap();
+        pool.schedule(Box::new(late)).unwrap();
+        pool.schedule(Box::new(relaxed)).unwrap();
+
+        assert!(late_waiter.await().unwrap().get_deadline_missed());
+        assert!(!relaxed_waiter.await().unwrap().get_deadline_missed());
+    }
+
+    #[test]
+    fn test_future_task() {
+        let pool = WorkStealingCpuPool::new(2, 1, Box::new(RandomDispatcher::new()));
+        let mut task = Task::from_future(Countdown(3));
+        let mut waiter = task.waiter().unwrap();
+        pool.schedule(Box::new(task)).unwrap();
+
+        // drive the waiter the way an async runtime would
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut context = Context::from_waker(&waker);
+        let wait_result = loop {
+            match Pin::new(&mut waiter).poll(&mut context) {
+                Poll::Ready(result) => break result.unwrap(),
+                Poll::Pending => thread::park(),
+            }
+        };
+        assert_eq!(4, wait_result.get_ticks());
+        assert_eq!(42, wait_result.into_result());
+    }
+
+    #[test]
+    fn test_spawn_from_task() {
+        let pool = WorkStealingCpuPool::new(2, 1, Box::new(RandomDispatcher::new()));
+        let mut children = vec![];
+        let mut sum = 0;
+        let mut parent = Task::new(move || {
+            if children.is_empty() {
+                for i in 1..5 {
+                    let child = Task::new(move || (TaskState::Complete, Some(i)));
+                    children.push(Some(spawn(child).ok().unwrap()));
+                }
+            }
+            // collect whatever has finished, and come back for the rest
+            for slot in children.iter_mut() {
+                let finished = match *slot {
+                    Some(ref waiter) => waiter.try_await(),
+                    None => continue,
+                };
+                if let Ok(result) = finished {
+                    sum += result.into_result();
+                    *slot = None;
+                }
+            }
+            if children.iter().all(|slot| slot.is_none()) {
+                (TaskState::Complete, Some(sum))
+            } else {
+                (TaskState::Incomplete, None)
+            }
+        });
+        let waiter = parent.waiter().unwrap();
+        pool.schedule(Box::new(parent)).unwrap();
+        assert_eq!(10, waiter.await().unwrap().into_result());
+
+        // nowhere to spawn onto outside of a task
+        assert!(spawn(Task::new(|| (TaskState::Complete, Some(0)))).is_err());
+    }
+
+    #[test]
+    fn test_batch_steal() {
+        let config = PoolConfig {
+            steal_policy: StealPolicyKind::StealHalf,
+            ..PoolConfig::default()
+        };
+        let pool = WorkStealingCpuPool::new_with_config(2, 1, Box::new(RandomDispatcher::new()), config);
+        // one executor ends up with all of the work, for the other to take
+        let mut parent = Task::new(|| {
+            let children: Vec<_> = (0..64)
+                .map(|i| {
+                    let child = Task::new(move || {
+                        let until = rdtsc() + from_seconds(0.001);
+                        while rdtsc() < until {}
+                        (TaskState::Complete, Some(i))
+                    });
+                    spawn(child).ok().unwrap()
+                })
+                .collect();
+            (TaskState::Complete, Some(children))
+        });
+        let waiter = parent.waiter().unwrap();
+        pool.schedule(Box::new(parent)).unwrap();
+        let parent_result = waiter.await().unwrap();
+        let n_steals: usize = parent_result.get_n_steals()
+            + parent_result
+                .into_result()
+                .into_iter()
+                .map(|child| child.await().unwrap().get_n_steals())
+                .sum::<usize>();
+
+        // the thief may still be busy writing down its last steal
+        wait_until(&|| pool.steal_stats().stolen_tasks == n_steals);
+        let stats = pool.steal_stats();
+        assert_eq!(n_steals, stats.stolen_tasks);
+        assert!(stats.largest_batch > 1);
+    }
+
+    #[test]
+    fn test_time_slice() {
+        let config = PoolConfig {
+            time_slice: Some(from_seconds(0.001)),
+            overrun_tolerance: from_seconds(0.001),
+            ..PoolConfig::default()
+        };
+        let pool = SegregatedCpuPool::new_with_config(1, Box::new(RandomDispatcher::new()), config);
+        let mut slices = 0;
+        let mut polite = Task::new(move || {
+            while !should_yield() {}
+            slices += 1;
+            if slices < 3 {
+                (TaskState::Incomplete, None)
+            } else {
+                (TaskState::Complete, Some(slices))
+            }
+        });
+        let mut greedy = Task::new(|| {
+            thread::sleep(Duration::from_millis(20));
+            (TaskState::Complete, Some(0))
+        });
+        let polite_waiter = polite.waiter().unwrap();
+        let greedy_waiter = greedy.waiter().unwrap();
+        pool.schedule(Box::new(polite)).unwrap();
+        pool.schedule(Box::new(greedy)).unwrap();
+
+        assert_eq!(0, polite_waiter.await().unwrap().get_overruns());
+        assert_eq!(1, greedy_waiter.await().unwrap().get_overruns());
+        assert_eq!(1, pool.count_overruns());
+        // nothing to yield to outside of a task
+        assert!(!should_yield());
+    }
+
+    #[test]
+    fn test_elephant_cores() {
+        let config = PoolConfig {
+            elephants: Some(ElephantPolicy {
+                max_ticks: 3,
+                max_cpu_time: u64::max_value(),
+                elephant_cores: 1,
+            }),
+            ..PoolConfig::default()
+        };
+        let pool = WorkStealingCpuPool::new_from_list_with_config(vec![0, 1, 2], Box::new(RandomDispatcher::new()), config);
+        let mut ticks = 0;
+        let mut elephant = Task::new(move || {
+            ticks += 1;
+            if ticks < 10 {
+                (TaskState::Incomplete, None)
+            } else {
+                (TaskState::Complete, current_cpu())
+            }
+        });
+        let mut mouse = Task::new(|| (TaskState::Complete, current_cpu()));
+        let elephant_waiter = elephant.waiter().unwrap();
+        let mouse_waiter = mouse.waiter().unwrap();
+        pool.schedule(Box::new(elephant)).unwrap();
+        pool.schedule(Box::new(mouse)).unwrap();
+
+        // the dispatcher only knows about the first two cores
+        assert!(*mouse_waiter.await().unwrap().get_result() < 2);
+        assert_eq!(2, elephant_waiter.await().unwrap().into_result());
+        assert_eq!(1, pool.count_reclassified());
+    }
+
+    #[test]
+    fn test_cancel_task() {
+        let pool = WorkStealingCpuPool::new(2, 1, Box::new(RandomDispatcher::new()));
+        let mut task = Task::new(|| (TaskState::Incomplete, None::<usize>));
+        let waiter = task.waiter().unwrap();
+        let cancel_handle = task.cancel_handle();
+        pool.schedule(Box::new(task)).unwrap();
+
+        cancel_handle.cancel();
+        match waiter.await() {
+            Err(TaskError::Cancelled) => {}
+            _ => panic!("expected the task to be cancelled"),
+        }
+    }
+
+    #[test]
+    fn test_drop_times_out() {
+        let mut config = PoolConfig::default();
+        config.drain_timeout = Duration::from_millis(10);
+        let pool = SegregatedCpuPool::new_with_config(1, Box::new(RandomDispatcher::new()), config);
+        let mut task = Task::new(|| (TaskState::Incomplete, None::<usize>));
+        let waiter = task.waiter().unwrap();
+        pool.schedule(Box::new(task)).unwrap();
+
+        drop(pool);
+        match waiter.await() {
+            Err(TaskError::TimedOut) => {}
+            _ => panic!("expected the task to time out"),
+        }
+    }
+
+    #[test]
+    fn test_shutdown_abort() {
+        let mut pool = SegregatedCpuPool::new(1, Box::new(RandomDispatcher::new()));
+        let mut task = Task::new(|| (TaskState::Incomplete, None::<usize>));
+        let waiter = task.waiter().unwrap();
+        pool.schedule(Box::new(task)).unwrap();
+
+        pool.shutdown(ShutdownMode::Abort);
+        match waiter.await() {
+            Err(TaskError::PoolShutDown) => {}
+            _ => panic!("expected the task to be aborted"),
         }
     }
 }
-// TODO: Implement Drop for CPU Pool
@@ -13,6 +13,10 @@
  * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
  */
 
+#[cfg(target_arch = "x86")]
+use std::arch::x86::_rdtsc;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::_rdtsc;
 use std::sync::{Once, ONCE_INIT};
 use time::PreciseTime;
 
@@ -66,18 +70,17 @@ pub fn cycles_per_second() -> u64 {
 /// Return a 64-bit timestamp using the rdtsc instruction.
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 pub fn rdtsc() -> u64 {
-    unsafe {
-        let lo: u32;
-        let hi: u32;
-        asm!("rdtsc" : "={eax}"(lo), "={edx}"(hi) : : : "volatile");
-        (((hi as u64) << 32) | lo as u64)
-    }
+    unsafe { _rdtsc() }
 }
 
 pub fn to_seconds(cycles: u64) -> f64 {
     cycles as f64 / cycles_per_second() as f64
 }
 
+pub fn from_seconds(seconds: f64) -> u64 {
+    (seconds * cycles_per_second() as f64) as u64
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
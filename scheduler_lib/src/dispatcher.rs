@@ -1,10 +1,22 @@
-use super::executor::Executor;
+use super::executor::{Executor, IdleReport};
 use rand::prelude::thread_rng;
+use cycles::rdtsc;
+use rand::seq::sample_indices;
 use rand::Rng;
+use std::cmp::{min, Reverse};
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+use std::u64;
 
-pub trait Dispatcher {
-    fn flush(self) -> Vec<Executor>;
+// Pools may be scheduled into from many threads at once, so select has to
+// cope with being called concurrently.
+pub trait Dispatcher: Send + Sync {
+    // hands the fleet back to the caller, leaving the dispatcher empty
+    fn flush(&mut self) -> Vec<Executor>;
     fn inject_fleet(&mut self, fleet: Vec<Executor>);
+    fn fleet(&self) -> &[Executor];
     fn select(&self) -> Option<&Executor>;
 }
 
@@ -19,14 +31,18 @@ impl RandomDispatcher {
 }
 
 impl Dispatcher for RandomDispatcher {
-    fn flush(self) -> Vec<Executor> {
-        self.fleet
+    fn flush(&mut self) -> Vec<Executor> {
+        mem::replace(&mut self.fleet, vec![])
     }
 
     fn inject_fleet(&mut self, fleet: Vec<Executor>) {
         self.fleet = fleet;
     }
 
+    fn fleet(&self) -> &[Executor] {
+        &self.fleet
+    }
+
     fn select(&self) -> Option<&Executor> {
         thread_rng().choose(&self.fleet)
     }
@@ -45,17 +61,232 @@ impl LoadAwareDispatcher {
 }
 
 impl Dispatcher for LoadAwareDispatcher {
-    fn flush(self) -> Vec<Executor> {
-        self.fleet
+    fn flush(&mut self) -> Vec<Executor> {
+        mem::replace(&mut self.fleet, vec![])
     }
 
     fn inject_fleet(&mut self, fleet: Vec<Executor>) {
         self.fleet = fleet;
     }
 
+    fn fleet(&self) -> &[Executor] {
+        &self.fleet
+    }
+
     fn select(&self) -> Option<&Executor> {
-        self.fleet
-            .iter()
+        // producers racing each other see the same counts, so start looking
+        // at a random executor rather than sending every one of them to the
+        // first idle executor in the fleet.
+        if self.fleet.is_empty() {
+            return None;
+        }
+        let start = thread_rng().gen_range(0, self.fleet.len());
+        let (tail, head) = self.fleet.split_at(start);
+        head.iter()
+            .chain(tail.iter())
             .min_by_key(|executor| executor.count_tasks())
     }
 }
+
+// Routes work to the executor with the most slack: the one whose most urgent
+// queued deadline is furthest away. Executors holding no deadline work have
+// all the slack in the world, and ties go to the least loaded executor.
+pub struct DeadlineAwareDispatcher {
+    fleet: Vec<Executor>,
+}
+
+impl DeadlineAwareDispatcher {
+    pub fn new() -> DeadlineAwareDispatcher {
+        DeadlineAwareDispatcher { fleet: vec![] }
+    }
+}
+
+impl Dispatcher for DeadlineAwareDispatcher {
+    fn flush(&mut self) -> Vec<Executor> {
+        mem::replace(&mut self.fleet, vec![])
+    }
+
+    fn inject_fleet(&mut self, fleet: Vec<Executor>) {
+        self.fleet = fleet;
+    }
+
+    fn fleet(&self) -> &[Executor] {
+        &self.fleet
+    }
+
+    fn select(&self) -> Option<&Executor> {
+        let now = rdtsc();
+        self.fleet.iter().max_by_key(|executor| {
+            // a deadline that has already passed leaves no slack at all
+            let slack = executor
+                .earliest_deadline()
+                .map_or(u64::MAX, |deadline| deadline.saturating_sub(now));
+            (slack, Reverse(executor.count_tasks()))
+        })
+    }
+}
+
+// Hands work to each executor in turn.
+pub struct RoundRobinDispatcher {
+    fleet: Vec<Executor>,
+    next: AtomicUsize,
+}
+
+impl RoundRobinDispatcher {
+    pub fn new() -> RoundRobinDispatcher {
+        RoundRobinDispatcher {
+            fleet: vec![],
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Dispatcher for RoundRobinDispatcher {
+    fn flush(&mut self) -> Vec<Executor> {
+        mem::replace(&mut self.fleet, vec![])
+    }
+
+    fn inject_fleet(&mut self, fleet: Vec<Executor>) {
+        self.fleet = fleet;
+        self.next.store(0, Ordering::Relaxed);
+    }
+
+    fn fleet(&self) -> &[Executor] {
+        &self.fleet
+    }
+
+    fn select(&self) -> Option<&Executor> {
+        if self.fleet.is_empty() {
+            return None;
+        }
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        Some(&self.fleet[next % self.fleet.len()])
+    }
+}
+
+// Like LoadAwareDispatcher, but only looks at `sample_size` executors picked
+// at random, so that a select does not cost a scan of the whole fleet.
+pub struct SampledLoadAwareDispatcher {
+    fleet: Vec<Executor>,
+    sample_size: usize,
+}
+
+impl SampledLoadAwareDispatcher {
+    pub fn new(sample_size: usize) -> SampledLoadAwareDispatcher {
+        SampledLoadAwareDispatcher {
+            fleet: vec![],
+            sample_size,
+        }
+    }
+}
+
+impl Dispatcher for SampledLoadAwareDispatcher {
+    fn flush(&mut self) -> Vec<Executor> {
+        mem::replace(&mut self.fleet, vec![])
+    }
+
+    fn inject_fleet(&mut self, fleet: Vec<Executor>) {
+        self.fleet = fleet;
+    }
+
+    fn fleet(&self) -> &[Executor] {
+        &self.fleet
+    }
+
+    fn select(&self) -> Option<&Executor> {
+        least_loaded_of_sample(&self.fleet, self.sample_size)
+    }
+}
+
+// Samples two executors and takes the less loaded one, which already gets
+// most of the benefit of looking at all of them.
+pub struct PowerOfTwoDispatcher {
+    fleet: Vec<Executor>,
+}
+
+impl PowerOfTwoDispatcher {
+    pub fn new() -> PowerOfTwoDispatcher {
+        PowerOfTwoDispatcher { fleet: vec![] }
+    }
+}
+
+impl Dispatcher for PowerOfTwoDispatcher {
+    fn flush(&mut self) -> Vec<Executor> {
+        mem::replace(&mut self.fleet, vec![])
+    }
+
+    fn inject_fleet(&mut self, fleet: Vec<Executor>) {
+        self.fleet = fleet;
+    }
+
+    fn fleet(&self) -> &[Executor] {
+        &self.fleet
+    }
+
+    fn select(&self) -> Option<&Executor> {
+        least_loaded_of_sample(&self.fleet, 2)
+    }
+}
+
+fn least_loaded_of_sample(fleet: &[Executor], sample_size: usize) -> Option<&Executor> {
+    let sample_size = min(sample_size, fleet.len());
+    sample_indices(&mut thread_rng(), fleet.len(), sample_size)
+        .into_iter()
+        .map(|i| &fleet[i])
+        .min_by_key(|executor| executor.count_tasks())
+}
+
+// Join-idle-queue: executors that run out of work put themselves on an idle
+// queue, and work goes to whoever has been waiting there longest. Selects
+// never look at executor load; when nobody is idle, work goes to a random
+// executor.
+pub struct JoinIdleQueueDispatcher {
+    fleet: Vec<Executor>,
+    send_idle_queue: Sender<usize>,
+    idle_queue: Mutex<Receiver<usize>>,
+}
+
+impl JoinIdleQueueDispatcher {
+    pub fn new() -> JoinIdleQueueDispatcher {
+        let (send_idle_queue, idle_queue) = channel();
+        JoinIdleQueueDispatcher {
+            fleet: vec![],
+            send_idle_queue,
+            idle_queue: Mutex::new(idle_queue),
+        }
+    }
+}
+
+impl Dispatcher for JoinIdleQueueDispatcher {
+    fn flush(&mut self) -> Vec<Executor> {
+        // reports from the old fleet would point at the wrong executors
+        let (send_idle_queue, idle_queue) = channel();
+        self.send_idle_queue = send_idle_queue;
+        self.idle_queue = Mutex::new(idle_queue);
+        mem::replace(&mut self.fleet, vec![])
+    }
+
+    fn inject_fleet(&mut self, fleet: Vec<Executor>) {
+        for (id, executor) in fleet.iter().enumerate() {
+            let report = IdleReport {
+                queue: self.send_idle_queue.clone(),
+                id,
+            };
+            // an executor that is already gone simply never shows up as idle
+            let _ = executor.report_idle(report);
+        }
+        self.fleet = fleet;
+    }
+
+    fn fleet(&self) -> &[Executor] {
+        &self.fleet
+    }
+
+    fn select(&self) -> Option<&Executor> {
+        let idle = self.idle_queue.lock().unwrap().try_recv();
+        match idle {
+            Ok(id) => self.fleet.get(id),
+            Err(_) => thread_rng().choose(&self.fleet),
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use std::fmt;
+
+// Things going wrong inside the scheduler that nobody is waiting to hear
+// about, e.g. a waiter that went away before its task finished.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SchedulerEvent {
+    // a task was ticked, but its state remained unstarted
+    UnexpectedUnstarted,
+    // an executor was started with fewer peers than it was promised
+    StealerChannelClosed,
+    // an executor's handle went away without shutting it down, so no more
+    // work can reach it; it finishes what it has and stops
+    WorkChannelClosed,
+    // the task's waiter was dropped before the result could be sent
+    ResultChannelClosed,
+    // the task finished, but nobody ever asked for a waiter
+    MissingResultChannel,
+    // the task said it was complete without producing a result
+    EmptyResult,
+    // the task finished with an error, or by panicking
+    TaskFailed { panicked: bool },
+}
+
+// Receives the events of every executor in a pool, on the executor's own
+// thread, so implementations should be quick about it.
+pub trait SchedulerEvents: Send + Sync {
+    fn event(&self, cpu: usize, event: SchedulerEvent);
+}
+
+impl fmt::Debug for SchedulerEvents {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SchedulerEvents")
+    }
+}
+
+// The default: ignores everything.
+pub struct SilentEvents;
+
+impl SchedulerEvents for SilentEvents {
+    fn event(&self, _cpu: usize, _event: SchedulerEvent) {}
+}
+
+// Writes every event to stderr, out of the way of whatever goes to stdout.
+pub struct StderrEvents;
+
+impl SchedulerEvents for StderrEvents {
+    fn event(&self, cpu: usize, event: SchedulerEvent) {
+        eprintln!("executor on cpu {}: {:?}", cpu, event);
+    }
+}
@@ -1,59 +1,349 @@
-use super::task::{Iterable, TaskState};
-use crossbeam_deque::{Deque, Steal, Stealer};
+use super::cpupool::PoolConfig;
+use super::events::{SchedulerEvent, SchedulerEvents};
+use super::injector::Injector;
+use super::queue::{WorkQueue, WorkStealer, PRIORITIES};
+use super::stats::{CycleHistogram, ExecutorSnapshot};
+use super::steal::{StealPolicy, StealStats, Victim};
+use super::task::{AbortReason, Iterable, TaskState};
+use super::trace::{task_id, ExecutorTrace, TraceBuffer, TraceEvent};
+use crossbeam_deque::Steal;
+use cycles::{from_seconds, rdtsc};
 use libc::{cpu_set_t, pthread_setaffinity_np, CPU_SET, CPU_ZERO};
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
+use std::cmp::min;
 use std::mem;
 use std::os::unix::thread::JoinHandleExt;
-use std::sync::atomic::AtomicBool;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
 use std::sync::atomic::Ordering;
-use std::sync::mpsc::{channel, Receiver, SendError, Sender};
-use std::sync::Arc;
+use std::sync::atomic::{fence, AtomicBool, AtomicU64, AtomicUsize};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, SendError, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::thread::Thread;
+use std::time::Duration;
+use std::u64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShutdownMode {
+    // finish every queued and in-flight task, then exit
+    Drain,
+    // cancel every queued task, then exit
+    Abort,
+}
+
+// What an executor thread is actually told. Aborts carry the reason that
+// gets reported to the waiters of the tasks they throw away.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ShutdownSignal {
+    Drain,
+    Abort(AbortReason),
+}
+
+// An idle executor first spins, then yields its core, then parks until it
+// is handed work or a peer has something to steal. Spinning keeps wake-up
+// latency low; parking keeps idle pools from burning every core.
+#[derive(Clone, Debug)]
+pub struct IdleStrategy {
+    pub spin_iterations: usize,
+    pub yield_iterations: usize,
+    // upper bound on a single park, in case a wake-up is missed
+    pub park_timeout: Duration,
+}
+
+impl Default for IdleStrategy {
+    fn default() -> IdleStrategy {
+        IdleStrategy {
+            spin_iterations: 100_000,
+            yield_iterations: 1_000,
+            park_timeout: Duration::from_millis(10),
+        }
+    }
+}
+
+// When a task counts as an elephant: once it has been ticked `max_ticks`
+// times, or has used `max_cpu_time` cycles, whichever comes first. Elephants
+// are demoted to Priority::Low, and a work-stealing pool can set
+// `elephant_cores` of its executors aside for them, away from the dispatcher.
+#[derive(Clone, Debug)]
+pub struct ElephantPolicy {
+    pub max_ticks: u32,
+    pub max_cpu_time: u64,
+    pub elephant_cores: usize,
+}
+
+impl Default for ElephantPolicy {
+    fn default() -> ElephantPolicy {
+        ElephantPolicy {
+            max_ticks: 8,
+            max_cpu_time: from_seconds(0.001),
+            elephant_cores: 0,
+        }
+    }
+}
+
+// Where executors send the elephants they find, when the pool has executors
+// set aside for them.
+#[derive(Clone)]
+pub struct ElephantLane {
+    channel: Sender<Box<Iterable>>,
+    unparker: Unparker,
+    load: WorkStealer,
+}
+
+// Where an executor announces that it has run out of work, under the id it
+// was given. See JoinIdleQueueDispatcher.
+pub struct IdleReport {
+    pub queue: Sender<usize>,
+    pub id: usize,
+}
+
+// Wakes an executor's thread if, and only if, it is parked.
+#[derive(Clone)]
+pub struct Unparker {
+    thread: Thread,
+    parked: Arc<AtomicBool>,
+}
+
+impl Unparker {
+    pub fn unpark(&self) {
+        // pairs with the fence in InnerExecutor::park: either the executor
+        // sees whatever we published before this call, or we see its flag.
+        fence(Ordering::SeqCst);
+        if self.parked.load(Ordering::Relaxed) && self.parked.swap(false, Ordering::SeqCst) {
+            self.thread.unpark();
+        }
+    }
+
+    fn is_parked(&self) -> bool {
+        self.parked.load(Ordering::Relaxed)
+    }
+}
+
+thread_local! {
+    // set on executor threads, so that running tasks can hand work to the
+    // executor they run on without going through the pool.
+    static LOCAL_EXECUTOR: RefCell<Option<Rc<LocalExecutor>>> = RefCell::new(None);
+    // rdtsc timestamp at which the task being ticked on this thread has
+    // used up its time slice
+    static SLICE_END: Cell<u64> = Cell::new(u64::MAX);
+}
+
+// The parts of an executor that tasks running on it can reach.
+struct LocalExecutor {
+    cpu: usize,
+    work_queue: WorkQueue,
+    stats: Arc<ExecutorStats>,
+    peers: Vec<Unparker>,
+    time_slice: Option<u64>,
+    overrun_tolerance: u64,
+    elephants: Option<ElephantPolicy>,
+    elephant_lanes: Vec<ElephantLane>,
+    trace: Option<Arc<TraceBuffer>>,
+    events: Arc<SchedulerEvents>,
+}
+
+fn local_executor() -> Option<Rc<LocalExecutor>> {
+    LOCAL_EXECUTOR.with(|local| local.borrow().clone())
+}
+
+// Pushes `task` onto the deque of the executor running on this thread,
+// where idle peers can steal it. Hands the task back when called from
+// anywhere else.
+pub(crate) fn push_local(task: Box<Iterable>) -> Result<(), Box<Iterable>> {
+    match local_executor() {
+        Some(local) => {
+            local.enqueue(task);
+            if let Some(peer) = local.peers.iter().find(|peer| peer.is_parked()) {
+                peer.unpark();
+            }
+            Ok(())
+        }
+        None => Err(task),
+    }
+}
+
+// Runs one task from this thread's executor in place, for tasks blocked on
+// work they pushed themselves. Returns false if there was nothing to run.
+pub(crate) fn run_local() -> bool {
+    match local_executor() {
+        Some(local) => match local.work_queue.pop() {
+            Some(task) => {
+                local.run_task(task);
+                true
+            }
+            None => false,
+        },
+        None => false,
+    }
+}
+
+// Passes `event` on to the pool of the executor running on this thread, if
+// any. Anywhere else there is nobody to tell.
+pub(crate) fn report_event(event: SchedulerEvent) {
+    if let Some(local) = local_executor() {
+        local.report(event);
+    }
+}
+
+pub(crate) fn on_executor() -> bool {
+    LOCAL_EXECUTOR.with(|local| local.borrow().is_some())
+}
+
+pub(crate) fn slice_end() -> u64 {
+    SLICE_END.with(|end| end.get())
+}
+
+// The cpu of the executor running on this thread, if any.
+pub fn current_cpu() -> Option<usize> {
+    LOCAL_EXECUTOR.with(|local| local.borrow().as_ref().map(|local| local.cpu))
+}
+
+// Counters shared between an Executor and its thread. They are only ever
+// bumped by the executor thread, so a relaxed load and store will do, and
+// nothing on the hot path pays for a locked read-modify-write.
+#[derive(Default)]
+struct ExecutorStats {
+    completed: AtomicUsize,
+    errors: AtomicUsize,
+    panics: AtomicUsize,
+    ticks: AtomicUsize,
+    busy_cycles: AtomicU64,
+    idle_cycles: AtomicU64,
+    steal_attempts: AtomicUsize,
+    steals: AtomicUsize,
+    steal_retries: AtomicUsize,
+    steal_empties: AtomicUsize,
+    stolen_tasks: AtomicUsize,
+    largest_steal_batch: AtomicUsize,
+    queue_high_water: AtomicUsize,
+    parks: AtomicUsize,
+    overruns: AtomicUsize,
+    reclassified: AtomicUsize,
+    latency: CycleHistogram,
+    cpu_time: CycleHistogram,
+}
+
+fn bump(counter: &AtomicUsize, by: usize) {
+    counter.store(counter.load(Ordering::Relaxed) + by, Ordering::Relaxed);
+}
+
+fn bump_cycles(counter: &AtomicU64, by: u64) {
+    counter.store(counter.load(Ordering::Relaxed).saturating_add(by), Ordering::Relaxed);
+}
+
+fn raise(counter: &AtomicUsize, to: usize) {
+    if to > counter.load(Ordering::Relaxed) {
+        counter.store(to, Ordering::Relaxed);
+    }
+}
 
 pub struct Executor {
     cpu: usize,
     busy: Arc<AtomicBool>,
-    not_acked_tasks: Cell<usize>,
-    thread: thread::JoinHandle<()>,
+    // tasks sent down the work channel that the executor has not picked up
+    // yet
+    pending_tasks: Arc<AtomicUsize>,
+    thread: Option<thread::JoinHandle<()>>,
     work_channel: Sender<Box<Iterable>>,
-    work_acknowledge_channel: Receiver<()>,
-    work_queue_peeker: Stealer<Box<Iterable>>,
-    stealer_channel: Sender<Stealer<Box<Iterable>>>,
+    handoff_channel: Sender<Box<Iterable>>,
+    work_queue_peeker: WorkStealer,
+    stealer_channel: Sender<(Victim, Unparker)>,
+    idle_report_channel: Sender<IdleReport>,
+    shutdown_channel: Sender<ShutdownSignal>,
+    exit_channel: Mutex<Receiver<()>>,
+    unparker: Unparker,
+    stats: Arc<ExecutorStats>,
+    trace: Option<Arc<TraceBuffer>>,
 }
 
 impl Executor {
-    pub fn new(cpu: usize, n_stealers: usize) -> (Executor, Stealer<Box<Iterable>>) {
-        let work_queue = Deque::<Box<Iterable>>::new();
+    // `elephant_lanes` are the executors this one sends its elephants to,
+    // if any, and `injector` is where it looks for work shared by the whole
+    // pool.
+    pub fn new(
+        cpu: usize,
+        n_stealers: usize,
+        config: &PoolConfig,
+        elephant_lanes: Vec<ElephantLane>,
+        injector: Option<Arc<Injector<Box<Iterable>>>>,
+    ) -> (Executor, WorkStealer) {
+        let work_queue = WorkQueue::new(config.queue_policy, config.aging_threshold);
         let work_stealer = work_queue.stealer();
         let work_queue_peeker = work_queue.stealer();
         let (send_work_channel, receive_work_channel) = channel();
+        // work other executors pass on to us, which nobody keeps count of
+        let (send_handoff_channel, receive_handoff_channel) = channel();
         let (send_stealer_channel, receive_stealer_channel) = channel();
-        let (send_acknowlege_work_channel, receive_acknowlege_work_channel) = channel();
+        let (send_idle_report_channel, receive_idle_report_channel) = channel();
+        let (send_shutdown_channel, receive_shutdown_channel) = channel();
+        // nothing is ever sent on the exit channel: the sender is dropped
+        // when the thread ends, which disconnects the receiver.
+        let (send_exit_channel, receive_exit_channel) = channel::<()>();
         let busy_flag = Arc::new(AtomicBool::new(false));
         let busy_flag_clone = busy_flag.clone();
+        let pending_tasks = Arc::new(AtomicUsize::new(0));
+        let pending_tasks_clone = pending_tasks.clone();
+        let parked_flag = Arc::new(AtomicBool::new(false));
+        let parked_flag_clone = parked_flag.clone();
+        let stats = Arc::new(ExecutorStats::default());
+        let idle = config.idle.clone();
+        let steal_policy = config.steal_policy;
+        let max_steal_batch = config.max_steal_batch;
+        let trace = config.trace_capacity.map(|capacity| Arc::new(TraceBuffer::new(capacity)));
+        let local = LocalExecutor {
+            cpu,
+            work_queue,
+            stats: stats.clone(),
+            peers: Vec::with_capacity(n_stealers),
+            time_slice: config.time_slice,
+            overrun_tolerance: config.overrun_tolerance,
+            elephants: config.elephants.clone(),
+            elephant_lanes,
+            trace: trace.clone(),
+            events: config.events.clone(),
+        };
 
         let t_handle = thread::spawn(move || {
+            let _exit_guard = send_exit_channel;
             let mut inner_executor = InnerExecutor::new(
-                cpu,
+                local,
                 busy_flag_clone,
-                work_queue,
+                parked_flag_clone,
+                idle,
+                steal_policy.build(),
+                max_steal_batch,
                 receive_work_channel,
+                receive_handoff_channel,
                 receive_stealer_channel,
-                send_acknowlege_work_channel,
+                injector,
+                pending_tasks_clone,
+                receive_idle_report_channel,
+                receive_shutdown_channel,
                 n_stealers,
             );
             inner_executor.run();
         });
+        let unparker = Unparker {
+            thread: t_handle.thread().clone(),
+            parked: parked_flag,
+        };
 
         let executor = Executor {
             cpu,
             busy: busy_flag,
-            not_acked_tasks: Cell::new(0),
-            thread: t_handle,
+            pending_tasks,
+            thread: Some(t_handle),
             work_channel: send_work_channel,
-            work_acknowledge_channel: receive_acknowlege_work_channel,
+            handoff_channel: send_handoff_channel,
             work_queue_peeker,
             stealer_channel: send_stealer_channel,
+            idle_report_channel: send_idle_report_channel,
+            shutdown_channel: send_shutdown_channel,
+            exit_channel: Mutex::new(receive_exit_channel),
+            unparker,
+            stats,
+            trace,
         };
 
         // set thread affinity
@@ -64,16 +354,104 @@ impl Executor {
         (executor, work_stealer)
     }
 
+    // Safe to call from any number of threads at once.
     pub fn schedule(&self, task: Box<Iterable>) -> Result<(), SendError<Box<Iterable>>> {
-        self.not_acked_tasks.set(self.not_acked_tasks.get() + 1);
-        self.work_channel.send(task)
+        // counted before the send, so the executor never picks up a task
+        // that is not counted yet
+        self.pending_tasks.fetch_add(1, Ordering::SeqCst);
+        let sent = self.work_channel.send(task);
+        if sent.is_err() {
+            self.pending_tasks.fetch_sub(1, Ordering::SeqCst);
+        }
+        self.unparker.unpark();
+        sent
+    }
+
+    pub fn send_stealer(&self, victim: Victim, unparker: Unparker) -> Result<(), SendError<(Victim, Unparker)>> {
+        self.stealer_channel.send((victim, unparker))
+    }
+
+    // from now on, every time the executor runs out of work it says so on
+    // `report.queue`, once, until it is handed more.
+    pub fn report_idle(&self, report: IdleReport) -> Result<(), SendError<IdleReport>> {
+        self.idle_report_channel.send(report)
     }
 
-    pub fn send_stealer(
-        &self,
-        stealer: Stealer<Box<Iterable>>,
-    ) -> Result<(), SendError<Stealer<Box<Iterable>>>> {
-        self.stealer_channel.send(stealer)
+    pub fn is_parked(&self) -> bool {
+        self.unparker.is_parked()
+    }
+
+    pub fn unpark(&self) {
+        self.unparker.unpark();
+    }
+
+    pub fn unparker(&self) -> Unparker {
+        self.unparker.clone()
+    }
+
+    pub fn count_parks(&self) -> usize {
+        self.stats.parks.load(Ordering::Relaxed)
+    }
+
+    pub fn count_panics(&self) -> usize {
+        self.stats.panics.load(Ordering::Relaxed)
+    }
+
+    pub fn count_overruns(&self) -> usize {
+        self.stats.overruns.load(Ordering::Relaxed)
+    }
+
+    pub fn steal_stats(&self) -> StealStats {
+        StealStats {
+            attempts: self.stats.steal_attempts.load(Ordering::Relaxed),
+            steals: self.stats.steals.load(Ordering::Relaxed),
+            retries: self.stats.steal_retries.load(Ordering::Relaxed),
+            empties: self.stats.steal_empties.load(Ordering::Relaxed),
+            stolen_tasks: self.stats.stolen_tasks.load(Ordering::Relaxed),
+            largest_batch: self.stats.largest_steal_batch.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn stats(&self) -> ExecutorSnapshot {
+        let stats = &self.stats;
+        ExecutorSnapshot {
+            cpu: self.cpu,
+            tasks_completed: stats.completed.load(Ordering::Relaxed),
+            errors: stats.errors.load(Ordering::Relaxed),
+            panics: stats.panics.load(Ordering::Relaxed),
+            ticks: stats.ticks.load(Ordering::Relaxed),
+            busy_cycles: stats.busy_cycles.load(Ordering::Relaxed),
+            idle_cycles: stats.idle_cycles.load(Ordering::Relaxed),
+            steals: self.steal_stats(),
+            queue_high_water: stats.queue_high_water.load(Ordering::Relaxed),
+            parks: stats.parks.load(Ordering::Relaxed),
+            overruns: stats.overruns.load(Ordering::Relaxed),
+            reclassified: stats.reclassified.load(Ordering::Relaxed),
+            latency: stats.latency.snapshot(),
+            cpu_time: stats.cpu_time.snapshot(),
+        }
+    }
+
+    // empty unless the pool was configured to trace
+    pub fn trace(&self) -> ExecutorTrace {
+        ExecutorTrace {
+            cpu: self.cpu,
+            events: self.trace.as_ref().map_or(vec![], |trace| trace.events()),
+        }
+    }
+
+    // number of tasks this executor found to be elephants
+    pub fn count_reclassified(&self) -> usize {
+        self.stats.reclassified.load(Ordering::Relaxed)
+    }
+
+    // lets other executors hand their elephants to this one
+    pub fn elephant_lane(&self) -> ElephantLane {
+        ElephantLane {
+            channel: self.handoff_channel.clone(),
+            unparker: self.unparker.clone(),
+            load: self.work_queue_peeker.clone(),
+        }
     }
 
     pub fn get_cpu(&self) -> usize {
@@ -81,27 +459,67 @@ impl Executor {
     }
 
     pub fn count_tasks(&self) -> usize {
-        // check acknowledged tasks, and change local task
-        // TODO: encapsulate the send/ack channels into 1 channel object
-        let acked_tasks = self.work_acknowledge_channel.try_iter().count();
-        self.not_acked_tasks
-            .set(self.not_acked_tasks.get() - acked_tasks);
-
-        // return the size of the work queue + size of tasks not acked yet
+        // return the size of the work queue + tasks not picked up yet
         // + 1 if the executor is busy.
         // HEADS UP: this count may not be precisely correct once read:
-        // the underlying thread may acknowledge tasks at any point and
-        // alter the size of the work queue. If work stealing is enabled,
-        // then other threads may also alter the size of the work queue
+        // the underlying thread may pick up tasks at any point and alter
+        // the size of the work queue, other producers may be scheduling
+        // onto this executor, and if work stealing is enabled, then other
+        // threads may also alter the size of the work queue
+        let pending_tasks = self.pending_tasks.load(Ordering::SeqCst);
         if self.busy.load(Ordering::Relaxed) {
-            self.not_acked_tasks.get() + self.work_queue_peeker.len() + 1
+            pending_tasks + self.work_queue_peeker.len() + 1
         } else {
-            self.not_acked_tasks.get() + self.work_queue_peeker.len()
+            pending_tasks + self.work_queue_peeker.len()
+        }
+    }
+
+    // the earliest deadline among this executor's queued work, if any
+    pub fn earliest_deadline(&self) -> Option<u64> {
+        self.work_queue_peeker.earliest_deadline()
+    }
+
+    pub fn shutdown(&self, mode: ShutdownMode) {
+        match mode {
+            ShutdownMode::Drain => self.signal(ShutdownSignal::Drain),
+            ShutdownMode::Abort => self.abort(AbortReason::PoolShutDown),
+        }
+    }
+
+    // stops the executor, reporting `reason` for every task it drops
+    pub fn abort(&self, reason: AbortReason) {
+        self.signal(ShutdownSignal::Abort(reason));
+    }
+
+    fn signal(&self, signal: ShutdownSignal) {
+        // the thread may already be gone, in which case there is nothing
+        // left to tell it.
+        let _ = self.shutdown_channel.send(signal);
+        self.unparker.unpark();
+    }
+
+    // waits up to `timeout` for the underlying thread to exit, returning
+    // whether it did. The thread still needs to be joined afterwards.
+    pub fn wait_exit(&self, timeout: Duration) -> bool {
+        match self.exit_channel.lock().unwrap().recv_timeout(timeout) {
+            Ok(_) => true,
+            Err(RecvTimeoutError::Disconnected) => true,
+            Err(RecvTimeoutError::Timeout) => false,
+        }
+    }
+
+    pub fn join(&mut self) -> thread::Result<()> {
+        match self.thread.take() {
+            Some(handle) => handle.join(),
+            None => Ok(()),
         }
     }
 
     unsafe fn pin_thread(&self) {
-        let tid = self.thread.as_pthread_t();
+        let tid = match self.thread {
+            Some(ref handle) => handle.as_pthread_t(),
+            None => return,
+        };
         let mut cpuset: cpu_set_t = mem::uninitialized();
         CPU_ZERO(&mut cpuset);
         CPU_SET(self.cpu, &mut cpuset);
@@ -109,117 +527,484 @@ impl Executor {
     }
 }
 
-// TODO: Implement Drop for Executor
+impl Drop for Executor {
+    fn drop(&mut self) {
+        // if a shutdown was already requested this is a no-op: an abort is
+        // never downgraded to a drain by the executor.
+        self.shutdown(ShutdownMode::Drain);
+        let _ = self.join();
+    }
+}
 
 struct InnerExecutor {
-    cpu: usize,
+    local: Rc<LocalExecutor>,
     busy: Arc<AtomicBool>,
-    work_queue: Deque<Box<Iterable>>,
+    parked: Arc<AtomicBool>,
+    idle: IdleStrategy,
+    steal_policy: Box<StealPolicy>,
+    max_steal_batch: usize,
     receive_work_channel: Receiver<Box<Iterable>>,
-    receive_stealer_channel: Receiver<Stealer<Box<Iterable>>>,
-    acknowlege_work_channel: Sender<()>,
-    stealers: Vec<Stealer<Box<Iterable>>>,
+    receive_handoff_channel: Receiver<Box<Iterable>>,
+    injector: Option<Arc<Injector<Box<Iterable>>>>,
+    pending_tasks: Arc<AtomicUsize>,
+    receive_idle_report_channel: Receiver<IdleReport>,
+    idle_report: Option<IdleReport>,
+    reported_idle: bool,
+    receive_shutdown_channel: Receiver<ShutdownSignal>,
+    shutdown: Option<ShutdownSignal>,
+    victims: Vec<Victim>,
+    // when the executor last ran out of work, if it has not found any since
+    idle_since: Option<u64>,
+    // the deepest the work queue has been, as last written to the stats
+    queue_high_water: usize,
 }
 
 impl InnerExecutor {
     fn new(
-        cpu: usize,
+        mut local: LocalExecutor,
         busy: Arc<AtomicBool>,
-        work_queue: Deque<Box<Iterable>>,
+        parked: Arc<AtomicBool>,
+        idle: IdleStrategy,
+        steal_policy: Box<StealPolicy>,
+        max_steal_batch: usize,
         receive_work_channel: Receiver<Box<Iterable>>,
-        receive_stealer_channel: Receiver<Stealer<Box<Iterable>>>,
-        acknowlege_work_channel: Sender<()>,
+        receive_handoff_channel: Receiver<Box<Iterable>>,
+        receive_stealer_channel: Receiver<(Victim, Unparker)>,
+        injector: Option<Arc<Injector<Box<Iterable>>>>,
+        pending_tasks: Arc<AtomicUsize>,
+        receive_idle_report_channel: Receiver<IdleReport>,
+        receive_shutdown_channel: Receiver<ShutdownSignal>,
         n_stealers: usize,
     ) -> InnerExecutor {
-        let stealers = Vec::with_capacity(n_stealers);
-        let mut inner_executor = InnerExecutor {
-            cpu,
+        let mut victims = Vec::with_capacity(n_stealers);
+        for _ in 0..n_stealers {
+            match receive_stealer_channel.recv() {
+                Ok((victim, unparker)) => {
+                    victims.push(victim);
+                    local.peers.push(unparker);
+                }
+                Err(_) => {
+                    local.report(SchedulerEvent::StealerChannelClosed);
+                }
+            }
+        }
+        InnerExecutor {
+            local: Rc::new(local),
             busy,
-            work_queue,
+            parked,
+            idle,
+            steal_policy,
+            max_steal_batch,
             receive_work_channel,
-            receive_stealer_channel,
-            acknowlege_work_channel,
-            stealers,
-        };
-        inner_executor.receive_stealers();
-        inner_executor
+            receive_handoff_channel,
+            injector,
+            pending_tasks,
+            receive_idle_report_channel,
+            idle_report: None,
+            reported_idle: false,
+            receive_shutdown_channel,
+            shutdown: None,
+            victims,
+            idle_since: None,
+            queue_high_water: 0,
+        }
     }
 
     fn run(&mut self) {
+        LOCAL_EXECUTOR.with(|current| *current.borrow_mut() = Some(self.local.clone()));
+
+        let mut idle_iterations = 0;
         loop {
+            self.receive_shutdown();
             self.receive_work();
-            let did_work = self.do_work();
-            if !did_work {
-                self.steal_work();
+            if let Some(ShutdownSignal::Abort(reason)) = self.shutdown {
+                self.abort_work(reason);
+                return;
+            }
+            self.record_queue_depth();
+            if self.local.work_queue.len() > 1 {
+                self.wake_idle_peer();
+            }
+
+            if self.do_work() || self.take_injected_work() || self.steal_work() {
+                self.end_idle();
+                idle_iterations = 0;
+                continue;
+            }
+            if self.idle_since.is_none() {
+                self.idle_since = Some(rdtsc());
+            }
+            if self.shutdown == Some(ShutdownSignal::Drain) {
+                self.end_idle();
+                return;
+            }
+            self.report_idle();
+
+            idle_iterations += 1;
+            if idle_iterations <= self.idle.spin_iterations {
+                continue;
+            } else if idle_iterations <= self.idle.spin_iterations + self.idle.yield_iterations {
+                thread::yield_now();
+            } else {
+                self.park();
             }
         }
     }
 
-    fn receive_work(&mut self) {
-        // TODO: handle errors that do not have to do with no messages
-        while let Ok(task) = self.receive_work_channel.try_recv() {
-            self.work_queue.push(task);
-            self.acknowlege_work_channel.send(()).unwrap();
+    fn park(&mut self) {
+        self.parked.store(true, Ordering::SeqCst);
+        fence(Ordering::SeqCst);
+
+        // anything published before our flag went up would not have woken
+        // us, so look once more before going to sleep.
+        self.receive_shutdown();
+        self.receive_work();
+        let has_work = self.shutdown.is_some()
+            || !self.local.work_queue.is_empty()
+            || self.injector.as_ref().map_or(false, |injector| !injector.is_empty())
+            || self.victims.iter().any(|victim| !victim.stealer().is_empty());
+        if !has_work {
+            self.local.trace(TraceEvent::Parked);
+            thread::park_timeout(self.idle.park_timeout);
+            self.local.trace(TraceEvent::Unparked);
+            bump(&self.local.stats.parks, 1);
+        }
+
+        self.parked.store(false, Ordering::SeqCst);
+        // keep the idle time of a long idle spell from showing up all at once
+        self.end_idle();
+        self.idle_since = Some(rdtsc());
+    }
+
+    fn end_idle(&mut self) {
+        if let Some(since) = self.idle_since.take() {
+            let idle_cycles = rdtsc().saturating_sub(since);
+            bump_cycles(&self.local.stats.idle_cycles, idle_cycles);
+        }
+    }
+
+    fn record_queue_depth(&mut self) {
+        let depth = self.local.work_queue.len();
+        if depth > self.queue_high_water {
+            self.queue_high_water = depth;
+            self.local.stats.queue_high_water.store(depth, Ordering::Relaxed);
+        }
+    }
+
+    fn wake_idle_peer(&self) {
+        if let Some(peer) = self.local.peers.iter().find(|peer| peer.is_parked()) {
+            peer.unpark();
         }
     }
 
-    fn receive_stealers(&mut self) {
-        for _ in 0..self.stealers.capacity() {
-            match self.receive_stealer_channel.recv() {
-                Ok(stealer) => {
-                    self.stealers.push(stealer);
+    // lets the dispatcher know we are out of work, once per idle spell
+    fn report_idle(&mut self) {
+        while let Ok(report) = self.receive_idle_report_channel.try_recv() {
+            self.idle_report = Some(report);
+            self.reported_idle = false;
+        }
+        if self.reported_idle {
+            return;
+        }
+        if let Some(ref report) = self.idle_report {
+            // nobody may be listening anymore, which is fine
+            let _ = report.queue.send(report.id);
+            self.reported_idle = true;
+        }
+    }
+
+    fn receive_work(&mut self) {
+        loop {
+            match self.receive_work_channel.try_recv() {
+                Ok(task) => {
+                    self.reported_idle = false;
+                    self.local.enqueue(task);
+                    // pushed first, so that the task is always counted somewhere
+                    self.pending_tasks.fetch_sub(1, Ordering::SeqCst);
                 }
-                Err(_) => {
-                    println!("Failed to recieve stealer.");
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.disconnected();
+                    break;
+                }
+            }
+        }
+        // the Executor keeps a sender of its own, so this one only closes
+        // along with the work channel
+        while let Ok(task) = self.receive_handoff_channel.try_recv() {
+            self.local.enqueue(task);
+        }
+    }
+
+    fn receive_shutdown(&mut self) {
+        loop {
+            match self.receive_shutdown_channel.try_recv() {
+                Ok(signal) => match self.shutdown {
+                    Some(ShutdownSignal::Abort(_)) => {}
+                    _ => self.shutdown = Some(signal),
+                },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.disconnected();
+                    break;
                 }
             }
         }
     }
 
+    // Dropping an Executor asks for a drain before its channels close, so
+    // getting here means the handle went away some other way. Nobody can
+    // reach us anymore, so finish up and stop rather than idle forever.
+    fn disconnected(&mut self) {
+        if self.shutdown.is_none() {
+            self.local.report(SchedulerEvent::WorkChannelClosed);
+            self.shutdown = Some(ShutdownSignal::Drain);
+        }
+    }
+
+    fn abort_work(&mut self, reason: AbortReason) {
+        while let Some(task) = self.local.work_queue.pop() {
+            task.abort(reason);
+        }
+        // whichever executor gets there first cancels the pool's shared work
+        if let Some(ref injector) = self.injector {
+            while let Some(task) = injector.pop() {
+                task.abort(reason);
+            }
+        }
+    }
+
     fn do_work(&mut self) -> bool {
-        match self.work_queue.steal() {
-            Steal::Data(mut task) => {
+        match self.local.work_queue.pop() {
+            Some(task) => {
                 self.busy.store(true, Ordering::Relaxed);
-                task.tick();
-                match task.get_state() {
-                    &TaskState::Incomplete => {
-                        self.work_queue.push(task);
-                    }
-                    &TaskState::Unstarted => {
-                        // this is unexpected, and may be an error
-                        println!("A task was started, but it's state remained unstarted");
-                    }
-                    &TaskState::Complete => {
-                        task.complete();
-                    }
-                    &TaskState::Error => {
-                        println!("A task was started, but resulted in an error");
-                        // current philosophy: errors should be handled
-                        // by the publisher of the task. Might be worth
-                        // adding some layers here to make the reason
-                        // for failure easy to determine.
-                    }
-                };
+                // ticks can nest, so busy time is only measured out here
+                let start = rdtsc();
+                self.local.run_task(task);
+                let busy_cycles = rdtsc().saturating_sub(start);
+                bump_cycles(&self.local.stats.busy_cycles, busy_cycles);
                 self.busy.store(false, Ordering::Relaxed);
+                // tasks may have pushed work of their own
+                self.record_queue_depth();
                 true
             }
-            Steal::Retry => false,
-            Steal::Empty => false,
+            None => false,
         }
     }
 
-    fn steal_work(&mut self) {
-        let stealer = self.stealers.iter().max_by_key(|s| s.len());
-        match stealer {
-            Some(stealer) => match stealer.steal() {
+    fn take_injected_work(&mut self) -> bool {
+        let task = match self.injector {
+            Some(ref injector) => injector.pop(),
+            None => None,
+        };
+        match task {
+            Some(task) => {
+                self.reported_idle = false;
+                self.local.enqueue(task);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn steal_work(&mut self) -> bool {
+        if self.victims.is_empty() {
+            return false;
+        }
+
+        // urgent work goes first, then the most important work
+        let mut outcome = self.steal_lane(&|victim| victim.stealer().deadline_len(), &|victim| {
+            victim.stealer().steal_deadline()
+        });
+        for &priority in PRIORITIES.iter() {
+            if let Steal::Empty = outcome {
+                outcome = self.steal_lane(&|victim| victim.stealer().level_len(priority), &|victim| {
+                    victim.stealer().steal(priority)
+                });
+            }
+        }
+
+        let stats = &self.local.stats;
+        bump(&stats.steal_attempts, 1);
+        match outcome {
+            Steal::Data(n_stolen) => {
+                bump(&stats.steals, 1);
+                bump(&stats.stolen_tasks, n_stolen);
+                raise(&stats.largest_steal_batch, n_stolen);
+                true
+            }
+            // someone else raced us for the work; there may be more
+            Steal::Retry => {
+                bump(&stats.steal_retries, 1);
+                true
+            }
+            Steal::Empty => {
+                bump(&stats.steal_empties, 1);
+                false
+            }
+        }
+    }
+
+    // Steals from whichever victim the policy picks, as many tasks as it asks
+    // for, in one go, and says how many it got. A race with another thief
+    // only counts as one if we came away with nothing.
+    fn steal_lane(
+        &mut self,
+        load: &Fn(&Victim) -> usize,
+        steal: &Fn(&Victim) -> Steal<Box<Iterable>>,
+    ) -> Steal<usize> {
+        let victim = match self.steal_policy.choose_victim(self.local.cpu, &self.victims, load) {
+            Some(i) => &self.victims[i],
+            None => return Steal::Empty,
+        };
+        let batch_size = min(self.steal_policy.batch_size(load(victim)), self.max_steal_batch).max(1);
+        let from = victim.get_cpu();
+        let mut n_stolen = 0;
+        let mut raced = false;
+        while n_stolen < batch_size {
+            match steal(victim) {
                 Steal::Data(mut task) => {
                     task.mark_stolen();
-                    self.work_queue.push(task);
+                    self.local.trace(TraceEvent::Stolen {
+                        task: task_id(&*task),
+                        from,
+                        to: self.local.cpu,
+                    });
+                    self.local.work_queue.push(task);
+                    n_stolen += 1;
+                }
+                Steal::Retry => {
+                    raced = true;
+                    break;
+                }
+                Steal::Empty => break,
+            }
+        }
+
+        if n_stolen > 0 {
+            Steal::Data(n_stolen)
+        } else if raced {
+            Steal::Retry
+        } else {
+            Steal::Empty
+        }
+    }
+}
+
+impl LocalExecutor {
+    // Ticks `task` once and deals with whatever state it is left in.
+    fn run_task(&self, mut task: Box<Iterable>) {
+        if task.is_cancelled() {
+            task.abort(AbortReason::Cancelled);
+            return;
+        }
+
+        // a task's own budget wins over the pool's. Ticks can nest when a task
+        // helps out with its own children, so the outer slice is put back after.
+        let start = rdtsc();
+        let budget = task.get_time_slice().or(self.time_slice);
+        let slice_end = budget.map_or(u64::MAX, |budget| start.saturating_add(budget));
+        let outer_slice_end = SLICE_END.with(|end| end.replace(slice_end));
+
+        // a panicking task must not take the executor (and every task queued
+        // behind it) down with it.
+        let mut panicked = false;
+        let id = task_id(&*task);
+        self.trace(TraceEvent::TickStart { task: id });
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| task.tick())) {
+            bump(&self.stats.panics, 1);
+            task.mark_panicked(payload);
+            panicked = true;
+        }
+        self.trace(TraceEvent::TickEnd { task: id });
+        bump(&self.stats.ticks, 1);
+
+        SLICE_END.with(|end| end.set(outer_slice_end));
+        if let Some(budget) = budget {
+            if rdtsc() - start > budget.saturating_add(self.overrun_tolerance) {
+                bump(&self.stats.overruns, 1);
+                task.mark_overrun();
+            }
+        }
+        match task.get_state() {
+            &TaskState::Incomplete => {
+                self.requeue(task);
+            }
+            &TaskState::Unstarted => {
+                // this is unexpected, and may be an error
+                self.report(SchedulerEvent::UnexpectedUnstarted);
+            }
+            &TaskState::Complete => {
+                bump(&self.stats.completed, 1);
+                self.record_finish(&*task);
+                // finishing a task may free up work that was waiting on it,
+                // which stays local to us.
+                for released in task.complete() {
+                    self.enqueue(released);
+                }
+            }
+            &TaskState::Error => {
+                // current philosophy: errors should be handled by the publisher
+                // of the task, so all we do is pass the failure along to its
+                // waiter.
+                if !panicked {
+                    bump(&self.stats.errors, 1);
+                }
+                self.report(SchedulerEvent::TaskFailed { panicked });
+                self.record_finish(&*task);
+                for released in task.complete() {
+                    self.enqueue(released);
                 }
-                Steal::Empty => {}
-                Steal::Retry => {}
-            },
-            None => {}
+            }
         };
     }
+
+    fn record_finish(&self, task: &Iterable) {
+        // a task that does not say when it was born has no latency to speak of
+        let birthday = task.get_birthday();
+        if birthday > 0 {
+            self.stats.latency.record(rdtsc().saturating_sub(birthday));
+        }
+        self.stats.cpu_time.record(task.get_cpu_time());
+        self.trace(TraceEvent::Completed { task: task_id(task) });
+    }
+
+    fn enqueue(&self, mut task: Box<Iterable>) {
+        task.mark_enqueued();
+        self.trace(TraceEvent::Enqueued { task: task_id(&*task) });
+        self.work_queue.push(task);
+    }
+
+    fn report(&self, event: SchedulerEvent) {
+        self.events.event(self.cpu, event);
+    }
+
+    fn trace(&self, event: TraceEvent) {
+        if let Some(ref trace) = self.trace {
+            trace.record(event);
+        }
+    }
+
+    // Puts a task that still has work left back in line, unless it has just
+    // turned out to be an elephant that belongs elsewhere.
+    fn requeue(&self, mut task: Box<Iterable>) {
+        if let Some(ref policy) = self.elephants {
+            let is_elephant = task.get_ticks() >= policy.max_ticks || task.get_cpu_time() >= policy.max_cpu_time;
+            if is_elephant && !task.is_elephant() {
+                task.mark_elephant();
+                bump(&self.stats.reclassified, 1);
+                if let Some(lane) = self.elephant_lanes.iter().min_by_key(|lane| lane.load.len()) {
+                    match lane.channel.send(task) {
+                        Ok(()) => {
+                            lane.unparker.unpark();
+                            return;
+                        }
+                        // the elephant cores are gone already; keep it ourselves
+                        Err(SendError(returned)) => task = returned,
+                    }
+                }
+            }
+        }
+        self.work_queue.push(task);
+    }
 }
@@ -0,0 +1,279 @@
+use super::cpupool::CpuPool;
+use super::executor::{on_executor, push_local, run_local};
+use super::task::{AbortReason, Iterable, TaskState};
+use cycles::rdtsc;
+use std::any::Any;
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+type ScopeFn = Box<FnOnce(&Scope<'static>) + Send>;
+
+// Runs `op` and waits for every task it spawns, and every task those spawn
+// in turn. Spawned tasks may borrow anything that outlives the call.
+//
+// Called from a running task, children go onto the deque of the executor
+// ticking that task, where idle peers can steal them. Called from anywhere
+// else they simply run in place; use WorkStealingCpuPool::scope to hand them
+// to a pool instead.
+pub fn scope<'scope, F, T>(op: F) -> T
+where
+    F: FnOnce(&Scope<'scope>) -> T,
+{
+    run_scope(None, op)
+}
+
+// Like scope, but children spawned outside of an executor are scheduled on
+// `pool`.
+pub fn scope_on<'scope, F, T>(pool: &'scope CpuPool, op: F) -> T
+where
+    F: FnOnce(&Scope<'scope>) -> T,
+{
+    run_scope(Some(pool), op)
+}
+
+// Runs `a` and `b`, potentially in parallel: `b` is offered to idle peers
+// while the current thread runs `a`.
+pub fn join<A, B, RA, RB>(a: A, b: B) -> (RA, RB)
+where
+    A: FnOnce() -> RA,
+    B: FnOnce() -> RB + Send,
+    RB: Send,
+{
+    let mut result_b = None;
+    let result_a = scope(|s| {
+        s.spawn(|_| result_b = Some(b()));
+        a()
+    });
+    (result_a, result_b.unwrap())
+}
+
+fn run_scope<'scope, F, T>(pool: Option<&'scope CpuPool>, op: F) -> T
+where
+    F: FnOnce(&Scope<'scope>) -> T,
+{
+    let scope = Scope {
+        pool,
+        latch: Arc::new(ScopeLatch::new()),
+        _marker: PhantomData,
+    };
+    let result = panic::catch_unwind(AssertUnwindSafe(|| op(&scope)));
+    // children may borrow from our caller's stack, so even a panicking
+    // scope has to wait for them before unwinding any further.
+    scope.latch.wait();
+    let child_panic = scope.latch.take_panic();
+    match (result, child_panic) {
+        (Err(payload), _) => panic::resume_unwind(payload),
+        (Ok(_), Some(payload)) => panic::resume_unwind(payload),
+        (Ok(result), None) => result,
+    }
+}
+
+pub struct Scope<'scope> {
+    pool: Option<&'scope CpuPool>,
+    latch: Arc<ScopeLatch>,
+    // invariant in 'scope, so children cannot be given shorter borrows
+    _marker: PhantomData<Cell<&'scope mut ()>>,
+}
+
+impl<'scope> Scope<'scope> {
+    pub fn spawn<F>(&self, func: F)
+    where
+        F: FnOnce(&Scope<'scope>) + Send + 'scope,
+    {
+        self.latch.increment();
+        let func: Box<FnOnce(&Scope<'scope>) + Send + 'scope> = Box::new(func);
+        // the scope does not return before every child has run, so nothing
+        // the child borrows can go away underneath it.
+        let func: ScopeFn = unsafe { mem::transmute(func) };
+        let job = Box::new(ScopeJob {
+            func: Some(func),
+            latch: self.latch.clone(),
+            state: TaskState::Unstarted,
+            birthday: rdtsc(),
+        });
+
+        let job = match push_local(job) {
+            Ok(()) => return,
+            Err(job) => job,
+        };
+        match self.pool {
+            // a job the pool refuses is dropped, which counts it as failed
+            Some(pool) => {
+                let _ = pool.schedule(job);
+            }
+            None => run_inline(job),
+        }
+    }
+}
+
+fn run_inline(mut job: Box<Iterable>) {
+    job.tick();
+    job.complete();
+}
+
+struct ScopeState {
+    pending: usize,
+    panic: Option<Box<Any + Send>>,
+}
+
+// Counts the children of a scope that have not finished yet, and keeps the
+// first panic among them.
+struct ScopeLatch {
+    state: Mutex<ScopeState>,
+    done: Condvar,
+}
+
+impl ScopeLatch {
+    fn new() -> ScopeLatch {
+        ScopeLatch {
+            state: Mutex::new(ScopeState {
+                pending: 0,
+                panic: None,
+            }),
+            done: Condvar::new(),
+        }
+    }
+
+    fn increment(&self) {
+        self.state.lock().unwrap().pending += 1;
+    }
+
+    fn set(&self, panic: Option<Box<Any + Send>>) {
+        let mut state = self.state.lock().unwrap();
+        if state.panic.is_none() {
+            state.panic = panic;
+        }
+        state.pending -= 1;
+        if state.pending == 0 {
+            self.done.notify_all();
+        }
+    }
+
+    fn wait(&self) {
+        if on_executor() {
+            // blocking here could leave our own children stranded on our
+            // deque, so run whatever is queued until they are all done.
+            while self.state.lock().unwrap().pending > 0 {
+                if !run_local() {
+                    thread::yield_now();
+                }
+            }
+        } else {
+            let mut state = self.state.lock().unwrap();
+            while state.pending > 0 {
+                state = self.done.wait(state).unwrap();
+            }
+        }
+    }
+
+    fn take_panic(&self) -> Option<Box<Any + Send>> {
+        self.state.lock().unwrap().panic.take()
+    }
+}
+
+struct ScopeJob {
+    func: Option<ScopeFn>,
+    latch: Arc<ScopeLatch>,
+    state: TaskState,
+    birthday: u64,
+}
+
+impl Iterable for ScopeJob {
+    fn tick(&mut self) {
+        if let Some(func) = self.func.take() {
+            let scope = Scope {
+                pool: None,
+                latch: self.latch.clone(),
+                _marker: PhantomData,
+            };
+            let result = panic::catch_unwind(AssertUnwindSafe(|| func(&scope)));
+            self.latch.set(result.err());
+        }
+        self.state = TaskState::Complete;
+    }
+
+    fn get_state(&self) -> &TaskState {
+        &self.state
+    }
+
+    fn complete(self: Box<Self>) -> Vec<Box<Iterable>> {
+        vec![]
+    }
+
+    fn mark_stolen(&mut self) {}
+
+    fn get_birthday(&self) -> u64 {
+        self.birthday
+    }
+
+    fn abort(mut self: Box<Self>, reason: AbortReason) {
+        if self.func.take().is_some() {
+            let message = format!("scope task aborted: {:?}", reason);
+            self.latch.set(Some(Box::new(message)));
+        }
+    }
+
+    // panics are caught in tick, so that the scope can rethrow them
+    fn mark_panicked(&mut self, _payload: Box<Any + Send>) {}
+}
+
+impl Drop for ScopeJob {
+    fn drop(&mut self) {
+        // dropped without ever running, e.g. by a pool that was shut down
+        if self.func.take().is_some() {
+            self.latch.set(Some(Box::new("scope task dropped before it ran")));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cpupool::WorkStealingCpuPool;
+    use dispatcher::RandomDispatcher;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use task::Task;
+
+    fn fib(n: u64) -> u64 {
+        if n < 2 {
+            return n;
+        }
+        let (a, b) = join(|| fib(n - 1), || fib(n - 2));
+        a + b
+    }
+
+    #[test]
+    fn test_scope_borrows() {
+        let pool = WorkStealingCpuPool::new(2, 1, Box::new(RandomDispatcher::new()));
+        let numbers: Vec<usize> = (1..101).collect();
+        let sum = AtomicUsize::new(0);
+        pool.scope(|s| {
+            for chunk in numbers.chunks(10) {
+                let sum = &sum;
+                s.spawn(move |_| {
+                    sum.fetch_add(chunk.iter().sum(), Ordering::Relaxed);
+                });
+            }
+        });
+        assert_eq!(5050, sum.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_join_inside_task() {
+        let pool = WorkStealingCpuPool::new(2, 1, Box::new(RandomDispatcher::new()));
+        let mut task = Task::new(|| (TaskState::Complete, Some(fib(15))));
+        let waiter = task.waiter().unwrap();
+        pool.schedule(Box::new(task)).unwrap();
+        assert_eq!(610, *waiter.await().unwrap().get_result());
+    }
+
+    #[test]
+    #[should_panic(expected = "child failed")]
+    fn test_child_panic() {
+        scope(|s| s.spawn(|_| panic!("child failed")));
+    }
+}
@@ -0,0 +1,446 @@
+use super::cancel::CancelHandle;
+use super::cpupool::CpuPool;
+use super::events::SchedulerEvent;
+use super::executor::report_event;
+use super::task::{AbortReason, Iterable, Priority, TaskState};
+use super::waiter::{result_channel, Lifecycle, ResultSender, TaskError, WaitResult, Waiter};
+use cycles::rdtsc;
+use std::any::Any;
+use std::sync::{Arc, Mutex};
+
+type NodeFn<R, E> = Box<FnMut(&[R]) -> (TaskState, Option<Result<R, E>>) + Send>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NodeId(usize);
+
+// Tasks wired together by their results. A node is only handed to an
+// executor once every node it depends on has completed, and is ticked with
+// their outputs in the order its dependencies were declared. The first
+// failure anywhere fails the whole graph: nodes downstream of it never run
+// and nodes already queued are cancelled.
+pub struct TaskGraph<R, E = ()>
+where
+    R: Clone + Send,
+    E: Send,
+{
+    funcs: Vec<NodeFn<R, E>>,
+    deps: Vec<Vec<usize>>,
+    send_result_channel: Option<ResultSender<WaitResult<GraphResult<R>>, E>>,
+    cancel_handle: CancelHandle,
+}
+
+impl<R, E> TaskGraph<R, E>
+where
+    R: Clone + Send + 'static,
+    E: Send + 'static,
+{
+    pub fn new() -> TaskGraph<R, E> {
+        TaskGraph {
+            funcs: vec![],
+            deps: vec![],
+            send_result_channel: None,
+            cancel_handle: CancelHandle::new(),
+        }
+    }
+
+    // Dependencies can only name nodes that were added before, which keeps
+    // the graph acyclic.
+    pub fn add<F>(&mut self, deps: &[NodeId], func: F) -> NodeId
+    where
+        F: FnMut(&[R]) -> (TaskState, Option<Result<R, E>>) + Send + 'static,
+    {
+        let id = self.funcs.len();
+        assert!(
+            deps.iter().all(|dep| dep.0 < id),
+            "a node can only depend on nodes added before it"
+        );
+        self.funcs.push(Box::new(func));
+        self.deps.push(deps.iter().map(|dep| dep.0).collect());
+        NodeId(id)
+    }
+
+    pub fn waiter(&mut self) -> Result<Waiter<WaitResult<GraphResult<R>>, E>, ()> {
+        match self.send_result_channel {
+            Some(_) => Err(()),
+            None => {
+                let (sender, waiter) = result_channel();
+                self.send_result_channel = Some(sender);
+                Ok(waiter)
+            }
+        }
+    }
+
+    // cancels every node that has not completed yet
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel_handle.clone()
+    }
+
+    // Hands the nodes without dependencies to `pool`; everything else is
+    // released by the executors as its inputs complete.
+    pub fn schedule(self, pool: &CpuPool) -> Result<(), ()> {
+        let n_nodes = self.funcs.len();
+        let mut dependents = vec![vec![]; n_nodes];
+        for (node, deps) in self.deps.iter().enumerate() {
+            for &dep in deps {
+                dependents[dep].push(node);
+            }
+        }
+
+        let graph = Arc::new(Graph {
+            cancel_handle: self.cancel_handle,
+            state: Mutex::new(GraphState {
+                funcs: self.funcs.into_iter().map(Some).collect(),
+                pending: self.deps.iter().map(|deps| deps.len()).collect(),
+                deps: self.deps,
+                dependents,
+                outputs: vec![None; n_nodes],
+                cpu_times: vec![0; n_nodes],
+                n_steals: vec![0; n_nodes],
+                ticks: 0,
+                overruns: 0,
+                remaining: n_nodes,
+                birthday: rdtsc(),
+                send_result_channel: self.send_result_channel,
+            }),
+        });
+
+        let roots = {
+            let mut state = graph.state.lock().unwrap();
+            if n_nodes == 0 {
+                state.finish();
+                return Ok(());
+            }
+            let ready: Vec<usize> = (0..n_nodes).filter(|&node| state.pending[node] == 0).collect();
+            ready
+                .into_iter()
+                .map(|node| state.release(&graph, node))
+                .collect::<Vec<GraphNode<R, E>>>()
+        };
+        for root in roots {
+            if pool.schedule(Box::new(root)).is_err() {
+                graph.fail(TaskError::PoolShutDown);
+                return Err(());
+            }
+        }
+        Ok(())
+    }
+}
+
+// What a graph produces once every node has completed. The WaitResult it
+// arrives in sums cpu time, ticks and steals over all nodes.
+pub struct GraphResult<R> {
+    outputs: Vec<R>,
+    cpu_times: Vec<u64>,
+    n_steals: Vec<usize>,
+}
+
+impl<R> GraphResult<R> {
+    pub fn get_output(&self, node: NodeId) -> &R {
+        &self.outputs[node.0]
+    }
+
+    // every node's output, indexed by the order nodes were added in
+    pub fn into_outputs(self) -> Vec<R> {
+        self.outputs
+    }
+
+    pub fn get_cpu_time(&self, node: NodeId) -> u64 {
+        self.cpu_times[node.0]
+    }
+
+    pub fn get_n_steals(&self, node: NodeId) -> usize {
+        self.n_steals[node.0]
+    }
+}
+
+struct Graph<R, E>
+where
+    R: Clone + Send,
+    E: Send,
+{
+    cancel_handle: CancelHandle,
+    state: Mutex<GraphState<R, E>>,
+}
+
+impl<R, E> Graph<R, E>
+where
+    R: Clone + Send,
+    E: Send,
+{
+    // the first failure is the one the waiter hears about
+    fn fail(&self, error: TaskError<E>) {
+        self.cancel_handle.cancel();
+        let mut state = self.state.lock().unwrap();
+        if let Some(channel) = state.send_result_channel.take() {
+            let _ = channel.send(Err(error));
+        }
+    }
+}
+
+struct GraphState<R, E>
+where
+    R: Clone + Send,
+    E: Send,
+{
+    // taken out as each node is released
+    funcs: Vec<Option<NodeFn<R, E>>>,
+    // dependencies of each node that have not completed yet
+    pending: Vec<usize>,
+    deps: Vec<Vec<usize>>,
+    dependents: Vec<Vec<usize>>,
+    outputs: Vec<Option<R>>,
+    cpu_times: Vec<u64>,
+    n_steals: Vec<usize>,
+    ticks: u32,
+    overruns: u32,
+    remaining: usize,
+    birthday: u64,
+    send_result_channel: Option<ResultSender<WaitResult<GraphResult<R>>, E>>,
+}
+
+impl<R, E> GraphState<R, E>
+where
+    R: Clone + Send,
+    E: Send,
+{
+    fn release(&mut self, graph: &Arc<Graph<R, E>>, node: usize) -> GraphNode<R, E> {
+        let inputs = self.deps[node]
+            .iter()
+            .map(|&dep| self.outputs[dep].clone().unwrap())
+            .collect();
+        GraphNode {
+            id: node,
+            graph: graph.clone(),
+            inputs,
+            func: self.funcs[node].take().unwrap(),
+            ticks: 0,
+            n_steals: 0,
+            overruns: 0,
+            cpu_time: 0,
+            birthday: rdtsc(),
+            elephant: false,
+            state: TaskState::Unstarted,
+            result: None,
+            error: None,
+        }
+    }
+
+    fn finish(&mut self) {
+        let outputs = self.outputs.drain(..).map(|output| output.unwrap()).collect();
+        let result = GraphResult {
+            outputs,
+            cpu_times: self.cpu_times.clone(),
+            n_steals: self.n_steals.clone(),
+        };
+        let now = rdtsc();
+        // nodes tick all over the pool, so there is no one set of steps in
+        // between to speak of
+        let lifecycle = Lifecycle {
+            created: self.birthday,
+            result_sent: now,
+            ..Lifecycle::default()
+        };
+        let wait_result = WaitResult::new(
+            result,
+            self.cpu_times.iter().sum(),
+            now - self.birthday,
+            self.ticks,
+            self.n_steals.iter().sum(),
+            false,
+            self.overruns,
+            lifecycle,
+        );
+        if let Some(channel) = self.send_result_channel.take() {
+            if channel.send(Ok(wait_result)).is_err() {
+                report_event(SchedulerEvent::ResultChannelClosed);
+            }
+        }
+    }
+}
+
+struct GraphNode<R, E>
+where
+    R: Clone + Send,
+    E: Send,
+{
+    id: usize,
+    graph: Arc<Graph<R, E>>,
+    inputs: Vec<R>,
+    func: NodeFn<R, E>,
+    ticks: u32,
+    n_steals: usize,
+    overruns: u32,
+    cpu_time: u64,
+    // when the node was released, rather than when the graph was built
+    birthday: u64,
+    elephant: bool,
+    state: TaskState,
+    result: Option<Result<R, E>>,
+    error: Option<TaskError<E>>,
+}
+
+impl<R, E> GraphNode<R, E>
+where
+    R: Clone + Send + 'static,
+    E: Send + 'static,
+{
+    fn succeed(self, output: R) -> Vec<Box<Iterable>> {
+        let mut state = self.graph.state.lock().unwrap();
+        if self.graph.cancel_handle.is_cancelled() {
+            // cancelled while we were running, with nothing left queued
+            // that would report it.
+            if let Some(channel) = state.send_result_channel.take() {
+                let _ = channel.send(Err(TaskError::Cancelled));
+            }
+            return vec![];
+        }
+
+        state.outputs[self.id] = Some(output);
+        state.cpu_times[self.id] = self.cpu_time;
+        state.n_steals[self.id] = self.n_steals;
+        state.ticks += self.ticks;
+        state.overruns += self.overruns;
+        state.remaining -= 1;
+        if state.remaining == 0 {
+            state.finish();
+            return vec![];
+        }
+
+        let mut released: Vec<Box<Iterable>> = vec![];
+        for dependent in state.dependents[self.id].clone() {
+            state.pending[dependent] -= 1;
+            if state.pending[dependent] == 0 {
+                released.push(Box::new(state.release(&self.graph, dependent)));
+            }
+        }
+        released
+    }
+}
+
+impl<R, E> Iterable for GraphNode<R, E>
+where
+    R: Clone + Send + 'static,
+    E: Send + 'static,
+{
+    fn tick(&mut self) {
+        self.ticks += 1;
+        let start = rdtsc();
+
+        let (state, result) = (self.func)(&self.inputs);
+        self.state = state;
+        self.result = result;
+
+        self.cpu_time += rdtsc() - start;
+    }
+
+    fn get_state(&self) -> &TaskState {
+        &self.state
+    }
+
+    fn complete(mut self: Box<Self>) -> Vec<Box<Iterable>> {
+        match (self.error.take(), self.result.take()) {
+            (Some(error), _) => self.graph.fail(error),
+            (None, Some(Ok(output))) => return self.succeed(output),
+            (None, Some(Err(error))) => self.graph.fail(TaskError::User(error)),
+            (None, None) => self.graph.fail(TaskError::Dropped),
+        }
+        vec![]
+    }
+
+    fn mark_stolen(&mut self) {
+        self.n_steals += 1;
+    }
+
+    fn get_priority(&self) -> Priority {
+        if self.elephant {
+            Priority::Low
+        } else {
+            Priority::Normal
+        }
+    }
+
+    fn mark_overrun(&mut self) {
+        self.overruns += 1;
+    }
+
+    fn get_ticks(&self) -> u32 {
+        self.ticks
+    }
+
+    fn get_cpu_time(&self) -> u64 {
+        self.cpu_time
+    }
+
+    fn get_birthday(&self) -> u64 {
+        self.birthday
+    }
+
+    fn is_elephant(&self) -> bool {
+        self.elephant
+    }
+
+    fn mark_elephant(&mut self) {
+        self.elephant = true;
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.graph.cancel_handle.is_cancelled()
+    }
+
+    fn abort(self: Box<Self>, reason: AbortReason) {
+        self.graph.fail(reason.into());
+    }
+
+    fn mark_panicked(&mut self, payload: Box<Any + Send>) {
+        self.state = TaskState::Error;
+        self.result = None;
+        self.error = Some(TaskError::Panicked(payload));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cpupool::WorkStealingCpuPool;
+    use dispatcher::RandomDispatcher;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn test_diamond() {
+        let pool = WorkStealingCpuPool::new(2, 1, Box::new(RandomDispatcher::new()));
+        let mut graph: TaskGraph<usize> = TaskGraph::new();
+        let a = graph.add(&[], |_| (TaskState::Complete, Some(Ok(1))));
+        let b = graph.add(&[a], |inputs| (TaskState::Complete, Some(Ok(inputs[0] + 1))));
+        let c = graph.add(&[a], |inputs| (TaskState::Complete, Some(Ok(inputs[0] * 10))));
+        let d = graph.add(&[b, c], |inputs| (TaskState::Complete, Some(Ok(inputs[0] + inputs[1]))));
+        let waiter = graph.waiter().unwrap();
+        graph.schedule(&pool).unwrap();
+
+        let wait_result = waiter.await().unwrap();
+        assert_eq!(4, wait_result.get_ticks());
+        assert_eq!(12, *wait_result.get_result().get_output(d));
+        assert_eq!(vec![1, 2, 10, 12], wait_result.into_result().into_outputs());
+    }
+
+    #[test]
+    fn test_upstream_failure() {
+        let pool = WorkStealingCpuPool::new(2, 1, Box::new(RandomDispatcher::new()));
+        let ran = Arc::new(AtomicBool::new(false));
+        let ran_clone = ran.clone();
+        let mut graph: TaskGraph<usize, &str> = TaskGraph::new();
+        let a = graph.add(&[], |_| (TaskState::Error, Some(Err("no input"))));
+        graph.add(&[a], move |_| {
+            ran_clone.store(true, Ordering::SeqCst);
+            (TaskState::Complete, Some(Ok(0)))
+        });
+        let waiter = graph.waiter().unwrap();
+        graph.schedule(&pool).unwrap();
+
+        match waiter.await() {
+            Err(TaskError::User(error)) => assert_eq!("no input", error),
+            _ => panic!("expected the graph to fail"),
+        }
+        drop(pool);
+        assert!(!ran.load(Ordering::SeqCst));
+    }
+}
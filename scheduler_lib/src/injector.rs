@@ -0,0 +1,176 @@
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct Slot<T> {
+    // tells producers and consumers whose turn it is at this slot: `pos`
+    // when it is free for the push at `pos`, `pos + 1` once that push is
+    // done and the value can be popped.
+    sequence: AtomicUsize,
+    value: UnsafeCell<Option<T>>,
+}
+
+// A bounded, lock-free queue that any number of threads may push onto and
+// pop from at once. Pools use it to hand work to whichever executor gets to
+// it first.
+pub struct Injector<T> {
+    slots: Box<[Slot<T>]>,
+    mask: usize,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+unsafe impl<T: Send> Send for Injector<T> {}
+unsafe impl<T: Send> Sync for Injector<T> {}
+
+impl<T> Injector<T> {
+    // `capacity` is rounded up to a power of two
+    pub fn new(capacity: usize) -> Injector<T> {
+        let capacity = capacity.max(2).next_power_of_two();
+        let slots: Vec<Slot<T>> = (0..capacity)
+            .map(|i| Slot {
+                sequence: AtomicUsize::new(i),
+                value: UnsafeCell::new(None),
+            })
+            .collect();
+        Injector {
+            slots: slots.into_boxed_slice(),
+            mask: capacity - 1,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    // Hands `value` back if the queue is full.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos & self.mask];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            let lag = sequence.wrapping_sub(pos) as isize;
+            if lag == 0 {
+                match self.tail.compare_exchange_weak(pos, pos.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        // winning the slot makes it ours until the sequence
+                        // moves on
+                        unsafe {
+                            *slot.value.get() = Some(value);
+                        }
+                        slot.sequence.store(pos.wrapping_add(1), Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => pos = current,
+                }
+            } else if lag < 0 {
+                // the value from a lap ago has not been popped yet
+                return Err(value);
+            } else {
+                pos = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        let mut pos = self.head.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos & self.mask];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            let lag = sequence.wrapping_sub(pos.wrapping_add(1)) as isize;
+            if lag == 0 {
+                match self.head.compare_exchange_weak(pos, pos.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        let value = unsafe { (*slot.value.get()).take() };
+                        // free for the push one lap from now
+                        slot.sequence.store(pos.wrapping_add(self.mask + 1), Ordering::Release);
+                        return value;
+                    }
+                    Err(current) => pos = current,
+                }
+            } else if lag < 0 {
+                return None;
+            } else {
+                pos = self.head.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    // only a hint while other threads are pushing or popping
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Relaxed);
+        let len = tail.wrapping_sub(head) as isize;
+        if len < 0 {
+            0
+        } else {
+            len as usize
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_full_queue() {
+        let injector = Injector::new(3);
+        for i in 0..4 {
+            assert_eq!(Ok(()), injector.push(i));
+        }
+        assert_eq!(Err(4), injector.push(4));
+        assert_eq!(Some(0), injector.pop());
+        assert_eq!(Ok(()), injector.push(4));
+        let rest: Vec<usize> = (0..5).map(|_| injector.pop()).flat_map(|value| value).collect();
+        assert_eq!(vec![1, 2, 3, 4], rest);
+    }
+
+    #[test]
+    fn test_many_producers_and_consumers() {
+        let injector = Arc::new(Injector::new(64));
+        let producers: Vec<_> = (0..4)
+            .map(|p| {
+                let injector = injector.clone();
+                thread::spawn(move || {
+                    for i in 0..1000 {
+                        let mut value = p * 1000 + i;
+                        while let Err(returned) = injector.push(value) {
+                            value = returned;
+                            thread::yield_now();
+                        }
+                    }
+                })
+            })
+            .collect();
+        let consumers: Vec<_> = (0..4)
+            .map(|_| {
+                let injector = injector.clone();
+                thread::spawn(move || {
+                    let mut popped = vec![];
+                    while popped.len() < 1000 {
+                        match injector.pop() {
+                            Some(value) => popped.push(value),
+                            None => thread::yield_now(),
+                        }
+                    }
+                    popped
+                })
+            })
+            .collect();
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        let mut popped: Vec<usize> = consumers
+            .into_iter()
+            .flat_map(|consumer| consumer.join().unwrap())
+            .collect();
+        popped.sort();
+        assert_eq!((0..4000).collect::<Vec<usize>>(), popped);
+        assert!(injector.is_empty());
+    }
+}
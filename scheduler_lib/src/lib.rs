@@ -1,13 +1,21 @@
-#![feature(asm)]
-
 extern crate crossbeam_deque;
 extern crate libc;
 extern crate rand;
 extern crate time;
 
+pub mod cancel;
 pub mod cpupool;
 pub mod cycles;
 pub mod dispatcher;
+pub mod events;
 pub mod executor;
+pub mod forkjoin;
+pub mod graph;
+pub mod injector;
+pub mod metrics;
+pub mod queue;
+pub mod stats;
+pub mod steal;
 pub mod task;
+pub mod trace;
 pub mod waiter;
@@ -0,0 +1,374 @@
+use super::cycles::to_seconds;
+use super::stats::{ExecutorSnapshot, HistogramSnapshot, PoolStats};
+use std::fmt::Write;
+
+#[cfg(feature = "metrics-server")]
+pub use self::server::MetricsServer;
+
+// Renders pool statistics in the Prometheus text exposition format. Counters
+// are per executor, labelled with its index in the pool and its cpu; the
+// latency and cpu time histograms cover the whole pool.
+pub fn prometheus(stats: &PoolStats) -> String {
+    let mut out = String::new();
+    counter(&mut out, stats, "scheduler_tasks_completed_total", "Tasks that ran to completion.", |e| {
+        e.tasks_completed as f64
+    });
+    counter(&mut out, stats, "scheduler_task_errors_total", "Tasks that finished with an error, not counting panics.", |e| {
+        e.errors as f64
+    });
+    counter(&mut out, stats, "scheduler_task_panics_total", "Tasks that panicked.", |e| e.panics as f64);
+    counter(&mut out, stats, "scheduler_ticks_total", "Task ticks run.", |e| e.ticks as f64);
+    counter(&mut out, stats, "scheduler_busy_seconds_total", "Time spent ticking tasks.", |e| {
+        to_seconds(e.busy_cycles)
+    });
+    counter(&mut out, stats, "scheduler_idle_seconds_total", "Time spent looking for work.", |e| {
+        to_seconds(e.idle_cycles)
+    });
+
+    header(&mut out, "scheduler_steal_attempts_total", "Steal attempts by outcome.", "counter");
+    for (i, executor) in stats.executors.iter().enumerate() {
+        let outcomes = [
+            ("stolen", executor.steals.steals),
+            ("retry", executor.steals.retries),
+            ("empty", executor.steals.empties),
+        ];
+        for &(outcome, value) in outcomes.iter() {
+            let _ = writeln!(
+                out,
+                "scheduler_steal_attempts_total{{executor=\"{}\",cpu=\"{}\",outcome=\"{}\"}} {}",
+                i, executor.cpu, outcome, value
+            );
+        }
+    }
+
+    counter(&mut out, stats, "scheduler_stolen_tasks_total", "Tasks taken from other executors.", |e| {
+        e.steals.stolen_tasks as f64
+    });
+    gauge(&mut out, stats, "scheduler_steal_batch_max", "Most tasks taken in one steal.", |e| {
+        e.steals.largest_batch as f64
+    });
+    gauge(&mut out, stats, "scheduler_queue_depth_high_water", "Most tasks queued at once.", |e| {
+        e.queue_high_water as f64
+    });
+    counter(&mut out, stats, "scheduler_parks_total", "Times the executor parked.", |e| e.parks as f64);
+    counter(&mut out, stats, "scheduler_overruns_total", "Ticks that ran past their time slice.", |e| {
+        e.overruns as f64
+    });
+    counter(&mut out, stats, "scheduler_reclassified_total", "Tasks reclassified as elephants.", |e| {
+        e.reclassified as f64
+    });
+
+    let total = stats.total();
+    histogram(
+        &mut out,
+        "scheduler_task_latency_seconds",
+        "Time from task creation to its finish.",
+        &total.latency,
+    );
+    histogram(
+        &mut out,
+        "scheduler_task_cpu_seconds",
+        "Cpu time tasks took overall.",
+        &total.cpu_time,
+    );
+    out
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn counter(out: &mut String, stats: &PoolStats, name: &str, help: &str, value: fn(&ExecutorSnapshot) -> f64) {
+    per_executor(out, stats, name, help, "counter", value);
+}
+
+fn gauge(out: &mut String, stats: &PoolStats, name: &str, help: &str, value: fn(&ExecutorSnapshot) -> f64) {
+    per_executor(out, stats, name, help, "gauge", value);
+}
+
+fn per_executor(
+    out: &mut String,
+    stats: &PoolStats,
+    name: &str,
+    help: &str,
+    kind: &str,
+    value: fn(&ExecutorSnapshot) -> f64,
+) {
+    header(out, name, help, kind);
+    for (i, executor) in stats.executors.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "{}{{executor=\"{}\",cpu=\"{}\"}} {}",
+            name,
+            i,
+            executor.cpu,
+            value(executor)
+        );
+    }
+}
+
+fn histogram(out: &mut String, name: &str, help: &str, histogram: &HistogramSnapshot) {
+    header(out, name, help, "histogram");
+    // Prometheus buckets are cumulative and inclusive, ours are neither; the
+    // difference at the bound itself is one cycle.
+    let mut cumulative = 0;
+    for (bucket, count) in histogram.counts.iter().enumerate() {
+        cumulative += *count;
+        if let Some(bound) = histogram.upper_bound(bucket) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, to_seconds(bound), cumulative);
+        }
+    }
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, histogram.count());
+    let _ = writeln!(out, "{}_sum {}", name, to_seconds(histogram.sum));
+    let _ = writeln!(out, "{}_count {}", name, histogram.count());
+}
+
+// Renders pool statistics as JSON: every executor's snapshot, and the pool
+// total under "total". Times are in seconds.
+pub fn json(stats: &PoolStats) -> String {
+    let executors: Vec<String> = stats.executors.iter().map(executor_json).collect();
+    format!(
+        "{{\"executors\":[{}],\"total\":{}}}",
+        executors.join(","),
+        executor_json(&stats.total())
+    )
+}
+
+fn executor_json(executor: &ExecutorSnapshot) -> String {
+    format!(
+        "{{\"cpu\":{},\"tasks_completed\":{},\"errors\":{},\"panics\":{},\"ticks\":{},\
+         \"busy_seconds\":{},\"idle_seconds\":{},\"utilization\":{},\
+         \"steals\":{{\"attempts\":{},\"steals\":{},\"retries\":{},\"empties\":{},\
+         \"stolen_tasks\":{},\"largest_batch\":{}}},\
+         \"queue_high_water\":{},\"parks\":{},\"overruns\":{},\"reclassified\":{},\
+         \"latency\":{},\"cpu_time\":{}}}",
+        executor.cpu,
+        executor.tasks_completed,
+        executor.errors,
+        executor.panics,
+        executor.ticks,
+        to_seconds(executor.busy_cycles),
+        to_seconds(executor.idle_cycles),
+        executor.utilization(),
+        executor.steals.attempts,
+        executor.steals.steals,
+        executor.steals.retries,
+        executor.steals.empties,
+        executor.steals.stolen_tasks,
+        executor.steals.largest_batch,
+        executor.queue_high_water,
+        executor.parks,
+        executor.overruns,
+        executor.reclassified,
+        histogram_json(&executor.latency),
+        histogram_json(&executor.cpu_time)
+    )
+}
+
+// the last bucket has no bound, so `bounds` is one shorter than `counts`
+fn histogram_json(histogram: &HistogramSnapshot) -> String {
+    let bounds: Vec<String> = (0..histogram.counts.len())
+        .filter_map(|bucket| histogram.upper_bound(bucket))
+        .map(|bound| to_seconds(bound).to_string())
+        .collect();
+    let counts: Vec<String> = histogram.counts.iter().map(|count| count.to_string()).collect();
+    format!(
+        "{{\"bounds\":[{}],\"counts\":[{}],\"sum\":{},\"count\":{}}}",
+        bounds.join(","),
+        counts.join(","),
+        to_seconds(histogram.sum),
+        histogram.count()
+    )
+}
+
+#[cfg(feature = "metrics-server")]
+mod server {
+    use super::prometheus;
+    use cpupool::CpuPool;
+    use std::io;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::thread::JoinHandle;
+    use std::time::Duration;
+
+    // how long a client may keep the server waiting on any one read or write;
+    // it serves one connection at a time, so a stalled client stalls everyone
+    const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+    // a scraper's request is a handful of short lines; anything past these
+    // limits is dropped unread
+    const MAX_HEADERS: usize = 100;
+    const MAX_REQUEST_BYTES: u64 = 16 * 1024;
+
+    // Serves a pool's statistics at GET /metrics, one connection at a time.
+    // Meant for a scraper on the same host, not for the open internet.
+    pub struct MetricsServer {
+        addr: SocketAddr,
+        stop: Arc<AtomicBool>,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl MetricsServer {
+        pub fn start<A: ToSocketAddrs>(pool: Arc<CpuPool>, addr: A) -> io::Result<MetricsServer> {
+            let listener = TcpListener::bind(addr)?;
+            let addr = listener.local_addr()?;
+            let stop = Arc::new(AtomicBool::new(false));
+            let thread_stop = stop.clone();
+            let thread = thread::Builder::new()
+                .name("metrics-server".to_string())
+                .spawn(move || {
+                    for stream in listener.incoming() {
+                        if thread_stop.load(Ordering::Acquire) {
+                            break;
+                        }
+                        // a client that goes away mid-request is its own problem
+                        if let Ok(stream) = stream {
+                            let _ = respond(stream, &*pool);
+                        }
+                    }
+                })?;
+            Ok(MetricsServer {
+                addr,
+                stop,
+                thread: Some(thread),
+            })
+        }
+
+        pub fn local_addr(&self) -> SocketAddr {
+            self.addr
+        }
+    }
+
+    impl Drop for MetricsServer {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::Release);
+            // accept only returns once somebody connects
+            let _ = TcpStream::connect(self.addr);
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    fn respond(mut stream: TcpStream, pool: &CpuPool) -> io::Result<()> {
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+        let mut request_line = String::new();
+        let mut reader = BufReader::new((&stream).take(MAX_REQUEST_BYTES));
+        reader.read_line(&mut request_line)?;
+        // closing on a request we have not read to the end makes some
+        // clients see a reset instead of the response
+        let mut header = String::new();
+        for _ in 0..MAX_HEADERS {
+            if reader.read_line(&mut header)? <= 2 {
+                break;
+            }
+            header.clear();
+        }
+        let mut parts = request_line.split_whitespace();
+        let (method, path) = (parts.next(), parts.next());
+        let (status, body) = if method == Some("GET") && path == Some("/metrics") {
+            ("200 OK", prometheus(&pool.stats()))
+        } else {
+            ("404 Not Found", String::from("not found\n"))
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )?;
+        stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use steal::StealStats;
+
+    fn pool_stats() -> PoolStats {
+        let mut latency = HistogramSnapshot {
+            counts: vec![0; 4],
+            sum: 3000,
+        };
+        latency.counts[0] = 1;
+        latency.counts[1] = 2;
+        let executor = ExecutorSnapshot {
+            cpu: 3,
+            tasks_completed: 3,
+            steals: StealStats {
+                attempts: 5,
+                steals: 2,
+                retries: 1,
+                empties: 2,
+                stolen_tasks: 4,
+                largest_batch: 3,
+            },
+            latency,
+            ..Default::default()
+        };
+        PoolStats {
+            executors: vec![executor.clone(), ExecutorSnapshot { cpu: 4, ..executor }],
+        }
+    }
+
+    #[test]
+    fn test_prometheus() {
+        let text = prometheus(&pool_stats());
+        assert!(text.contains("# TYPE scheduler_tasks_completed_total counter\n"));
+        assert!(text.contains("scheduler_tasks_completed_total{executor=\"1\",cpu=\"4\"} 3\n"));
+        assert!(text.contains("scheduler_steal_attempts_total{executor=\"0\",cpu=\"3\",outcome=\"retry\"} 1\n"));
+        // the pool-wide histogram adds both executors up, cumulatively
+        assert!(text.contains("# TYPE scheduler_task_latency_seconds histogram\n"));
+        let buckets: Vec<&str> = text
+            .lines()
+            .filter(|line| line.starts_with("scheduler_task_latency_seconds_bucket"))
+            .map(|line| line.rsplit(' ').next().unwrap())
+            .collect();
+        assert_eq!(vec!["2", "6", "6", "6"], buckets);
+        assert!(text.contains("scheduler_task_latency_seconds_bucket{le=\"+Inf\"} 6\n"));
+        assert!(text.contains("scheduler_task_latency_seconds_count 6\n"));
+    }
+
+    #[test]
+    fn test_json() {
+        let text = json(&pool_stats());
+        assert!(text.starts_with("{\"executors\":[{\"cpu\":3,\"tasks_completed\":3,"));
+        assert!(text.contains("\"total\":{\"cpu\":0,\"tasks_completed\":6,"));
+        assert!(text.contains("\"counts\":[2,4,0,0],\"sum\":"));
+        assert_eq!(text.matches('{').count(), text.matches('}').count());
+    }
+
+    #[cfg(feature = "metrics-server")]
+    #[test]
+    fn test_metrics_server() {
+        use cpupool::{CpuPool, WorkStealingCpuPool};
+        use dispatcher::RandomDispatcher;
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+        use std::sync::Arc;
+
+        let pool: Arc<CpuPool> = Arc::new(WorkStealingCpuPool::new(1, 1, Box::new(RandomDispatcher::new())));
+        let server = MetricsServer::start(pool, "127.0.0.1:0").unwrap();
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("scheduler_tasks_completed_total{executor=\"0\""));
+        assert!(get("/").starts_with("HTTP/1.1 404"));
+        // a client that never sends anything only holds up the next one for
+        // so long
+        let _silent = TcpStream::connect(server.local_addr()).unwrap();
+        assert!(get("/metrics").starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+}
@@ -0,0 +1,283 @@
+use super::task::{Iterable, Priority};
+use crossbeam_deque::{Deque, Steal, Stealer};
+use std::cell::Cell;
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BinaryHeap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::u64;
+
+pub const N_PRIORITIES: usize = 3;
+// most important first
+pub const PRIORITIES: [Priority; N_PRIORITIES] = [Priority::High, Priority::Normal, Priority::Low];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QueuePolicy {
+    // tick the most important work first
+    Priority,
+    // tick the task with the earliest deadline first; tasks without a
+    // deadline fall back to priority order once no deadline work is left
+    EarliestDeadlineFirst,
+}
+
+// An executor's local work: one FIFO deque per priority level, plus a
+// DeadlineHeap. Under QueuePolicy::EarliestDeadlineFirst, tasks with a
+// deadline go on the heap instead of their level, and the owner always
+// takes the earliest deadline first. Otherwise it takes from the most
+// important non-empty level, except that a level passed over
+// `aging_threshold` times in a row gets served next, so that low priority
+// work keeps making progress under load.
+pub struct WorkQueue {
+    policy: QueuePolicy,
+    deadlines: Arc<DeadlineHeap>,
+    levels: Vec<Deque<Box<Iterable>>>,
+    starvation: Vec<Cell<usize>>,
+    aging_threshold: usize,
+}
+
+impl WorkQueue {
+    pub fn new(policy: QueuePolicy, aging_threshold: usize) -> WorkQueue {
+        WorkQueue {
+            policy,
+            deadlines: Arc::new(DeadlineHeap::new()),
+            levels: (0..N_PRIORITIES).map(|_| Deque::new()).collect(),
+            starvation: (0..N_PRIORITIES).map(|_| Cell::new(0)).collect(),
+            aging_threshold,
+        }
+    }
+
+    pub fn stealer(&self) -> WorkStealer {
+        WorkStealer {
+            deadlines: self.deadlines.clone(),
+            levels: self.levels.iter().map(|level| level.stealer()).collect(),
+        }
+    }
+
+    pub fn push(&self, task: Box<Iterable>) {
+        match (self.policy, task.get_deadline()) {
+            (QueuePolicy::EarliestDeadlineFirst, Some(deadline)) => {
+                self.deadlines.push(deadline, task)
+            }
+            _ => self.levels[task.get_priority() as usize].push(task),
+        }
+    }
+
+    pub fn pop(&self) -> Option<Box<Iterable>> {
+        if let Some(task) = self.deadlines.pop() {
+            return Some(task);
+        }
+
+        let starved = (0..N_PRIORITIES).rev().find(|&level| {
+            self.starvation[level].get() >= self.aging_threshold && !self.levels[level].is_empty()
+        });
+        let level = match starved.or_else(|| (0..N_PRIORITIES).find(|&level| !self.levels[level].is_empty())) {
+            Some(level) => level,
+            None => return None,
+        };
+
+        self.starvation[level].set(0);
+        for lower in level + 1..N_PRIORITIES {
+            if !self.levels[lower].is_empty() {
+                self.starvation[lower].set(self.starvation[lower].get() + 1);
+            }
+        }
+        take(&self.levels[level])
+    }
+
+    pub fn len(&self) -> usize {
+        self.deadlines.len() + self.levels.iter().map(|level| level.len()).sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+struct DeadlineTask {
+    deadline: u64,
+    task: Box<Iterable>,
+}
+
+// BinaryHeap is a max-heap, so the earliest deadline has to compare greatest
+impl Ord for DeadlineTask {
+    fn cmp(&self, other: &DeadlineTask) -> CmpOrdering {
+        other.deadline.cmp(&self.deadline)
+    }
+}
+
+impl PartialOrd for DeadlineTask {
+    fn partial_cmp(&self, other: &DeadlineTask) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for DeadlineTask {
+    fn eq(&self, other: &DeadlineTask) -> bool {
+        self.deadline == other.deadline
+    }
+}
+
+impl Eq for DeadlineTask {}
+
+// Deadline work ordered earliest first. Unlike the deques this needs a
+// lock, but thieves only ever try_lock it, and its length and earliest
+// deadline are mirrored into atomics so that load checks never block.
+struct DeadlineHeap {
+    heap: Mutex<BinaryHeap<DeadlineTask>>,
+    len: AtomicUsize,
+    earliest: AtomicU64,
+}
+
+impl DeadlineHeap {
+    fn new() -> DeadlineHeap {
+        DeadlineHeap {
+            heap: Mutex::new(BinaryHeap::new()),
+            len: AtomicUsize::new(0),
+            earliest: AtomicU64::new(u64::MAX),
+        }
+    }
+
+    fn push(&self, deadline: u64, task: Box<Iterable>) {
+        let mut heap = self.heap.lock().unwrap();
+        heap.push(DeadlineTask { deadline, task });
+        self.publish(&heap);
+    }
+
+    fn pop(&self) -> Option<Box<Iterable>> {
+        // cheap check first: most pools never use deadlines at all
+        if self.len() == 0 {
+            return None;
+        }
+        let mut heap = self.heap.lock().unwrap();
+        let popped = heap.pop();
+        self.publish(&heap);
+        popped.map(|entry| entry.task)
+    }
+
+    fn try_steal(&self) -> Steal<Box<Iterable>> {
+        if self.len() == 0 {
+            return Steal::Empty;
+        }
+        match self.heap.try_lock() {
+            Ok(mut heap) => {
+                let popped = heap.pop();
+                self.publish(&heap);
+                match popped {
+                    Some(entry) => Steal::Data(entry.task),
+                    None => Steal::Empty,
+                }
+            }
+            Err(_) => Steal::Retry,
+        }
+    }
+
+    fn publish(&self, heap: &BinaryHeap<DeadlineTask>) {
+        self.len.store(heap.len(), Ordering::Relaxed);
+        let earliest = heap.peek().map_or(u64::MAX, |entry| entry.deadline);
+        self.earliest.store(earliest, Ordering::Relaxed);
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+}
+
+// Takes from the front of a deque, retrying whenever a thief gets in the way.
+fn take(deque: &Deque<Box<Iterable>>) -> Option<Box<Iterable>> {
+    loop {
+        match deque.steal() {
+            Steal::Data(task) => return Some(task),
+            Steal::Retry => {}
+            Steal::Empty => return None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct WorkStealer {
+    deadlines: Arc<DeadlineHeap>,
+    levels: Vec<Stealer<Box<Iterable>>>,
+}
+
+impl WorkStealer {
+    // takes the most urgent task with a deadline
+    pub fn steal_deadline(&self) -> Steal<Box<Iterable>> {
+        self.deadlines.try_steal()
+    }
+
+    pub fn deadline_len(&self) -> usize {
+        self.deadlines.len()
+    }
+
+    // the earliest deadline among queued tasks, if any have one
+    pub fn earliest_deadline(&self) -> Option<u64> {
+        match self.deadlines.earliest.load(Ordering::Relaxed) {
+            u64::MAX => None,
+            deadline => Some(deadline),
+        }
+    }
+
+    pub fn steal(&self, priority: Priority) -> Steal<Box<Iterable>> {
+        self.levels[priority as usize].steal()
+    }
+
+    pub fn level_len(&self, priority: Priority) -> usize {
+        self.levels[priority as usize].len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.deadlines.len() + self.levels.iter().map(|level| level.len()).sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use task::{Task, TaskState};
+
+    fn task_with_priority(priority: Priority) -> Box<Iterable> {
+        Box::new(Task::new(|| (TaskState::Complete, Some(()))).with_priority(priority))
+    }
+
+    #[test]
+    fn test_priority_order() {
+        let queue = WorkQueue::new(QueuePolicy::Priority, usize::max_value());
+        queue.push(task_with_priority(Priority::Low));
+        queue.push(task_with_priority(Priority::Normal));
+        queue.push(task_with_priority(Priority::High));
+
+        let order: Vec<Priority> = (0..3).map(|_| queue.pop().unwrap().get_priority()).collect();
+        assert_eq!(vec![Priority::High, Priority::Normal, Priority::Low], order);
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn test_aging() {
+        let queue = WorkQueue::new(QueuePolicy::Priority, 2);
+        queue.push(task_with_priority(Priority::Low));
+        for _ in 0..3 {
+            queue.push(task_with_priority(Priority::High));
+        }
+
+        let order: Vec<Priority> = (0..3).map(|_| queue.pop().unwrap().get_priority()).collect();
+        assert_eq!(vec![Priority::High, Priority::High, Priority::Low], order);
+    }
+
+    #[test]
+    fn test_earliest_deadline_first() {
+        let queue = WorkQueue::new(QueuePolicy::EarliestDeadlineFirst, usize::max_value());
+        for &deadline in &[30, 10, 20] {
+            let task = Task::new(|| (TaskState::Complete, Some(()))).with_deadline(deadline);
+            queue.push(Box::new(task));
+        }
+        queue.push(task_with_priority(Priority::High));
+        assert_eq!(Some(10), queue.stealer().earliest_deadline());
+
+        let order: Vec<Option<u64>> = (0..4).map(|_| queue.pop().unwrap().get_deadline()).collect();
+        assert_eq!(vec![Some(10), Some(20), Some(30), None], order);
+    }
+}
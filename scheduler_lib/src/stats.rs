@@ -0,0 +1,170 @@
+use super::steal::StealStats;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+// Histograms bucket rdtsc cycles by powers of two: bucket i holds whatever
+// took less than 2^(FIRST_BUCKET_SHIFT + i) cycles, and the last bucket
+// whatever took longer than that.
+const FIRST_BUCKET_SHIFT: u32 = 10;
+pub const HISTOGRAM_BUCKETS: usize = 32;
+
+// A histogram an executor records into without locking. Only the executor's
+// own thread records, so anybody else may read but must not write.
+pub(crate) struct CycleHistogram {
+    buckets: Vec<AtomicUsize>,
+    sum: AtomicU64,
+}
+
+impl Default for CycleHistogram {
+    fn default() -> CycleHistogram {
+        CycleHistogram {
+            buckets: (0..HISTOGRAM_BUCKETS).map(|_| AtomicUsize::new(0)).collect(),
+            sum: AtomicU64::new(0),
+        }
+    }
+}
+
+impl CycleHistogram {
+    pub fn record(&self, cycles: u64) {
+        let bits = 64 - cycles.leading_zeros();
+        let bucket = (bits.saturating_sub(FIRST_BUCKET_SHIFT) as usize).min(HISTOGRAM_BUCKETS - 1);
+        let count = &self.buckets[bucket];
+        count.store(count.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+        // saturates the same way merge does, so the sum never goes down
+        self.sum.store(self.sum.load(Ordering::Relaxed).saturating_add(cycles), Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            counts: self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect(),
+            sum: self.sum.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HistogramSnapshot {
+    // how many values fell in each bucket, not cumulative
+    pub counts: Vec<usize>,
+    // of all values, in cycles
+    pub sum: u64,
+}
+
+impl HistogramSnapshot {
+    pub fn count(&self) -> usize {
+        self.counts.iter().sum()
+    }
+
+    // in cycles, exclusive; None for the last bucket, which has no bound
+    pub fn upper_bound(&self, bucket: usize) -> Option<u64> {
+        if bucket + 1 < self.counts.len() {
+            Some(1 << (FIRST_BUCKET_SHIFT as usize + bucket))
+        } else {
+            None
+        }
+    }
+
+    fn merge(&mut self, other: &HistogramSnapshot) {
+        if self.counts.len() < other.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        for (count, other) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += *other;
+        }
+        self.sum = self.sum.saturating_add(other.sum);
+    }
+}
+
+// What one executor has been up to since it started. Cycles are rdtsc
+// cycles; see cycles::to_seconds.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExecutorSnapshot {
+    pub cpu: usize,
+    // tasks that ran to completion on this executor
+    pub tasks_completed: usize,
+    // tasks that finished with an error, not counting panics
+    pub errors: usize,
+    pub panics: usize,
+    pub ticks: usize,
+    // time spent ticking tasks, and time spent looking for something to tick
+    pub busy_cycles: u64,
+    pub idle_cycles: u64,
+    pub steals: StealStats,
+    // the most tasks the executor has had queued up at once
+    pub queue_high_water: usize,
+    pub parks: usize,
+    pub overruns: usize,
+    pub reclassified: usize,
+    // of the tasks that finished here, from creation to finish, and the cpu
+    // time they took overall
+    pub latency: HistogramSnapshot,
+    pub cpu_time: HistogramSnapshot,
+}
+
+impl ExecutorSnapshot {
+    // the share of its time the executor spent ticking tasks
+    pub fn utilization(&self) -> f64 {
+        let total = self.busy_cycles + self.idle_cycles;
+        if total == 0 {
+            0.
+        } else {
+            self.busy_cycles as f64 / total as f64
+        }
+    }
+
+    fn merge(mut self, other: &ExecutorSnapshot) -> ExecutorSnapshot {
+        self.tasks_completed += other.tasks_completed;
+        self.errors += other.errors;
+        self.panics += other.panics;
+        self.ticks += other.ticks;
+        self.busy_cycles += other.busy_cycles;
+        self.idle_cycles += other.idle_cycles;
+        self.steals = self.steals.merge(other.steals);
+        self.queue_high_water = self.queue_high_water.max(other.queue_high_water);
+        self.parks += other.parks;
+        self.overruns += other.overruns;
+        self.reclassified += other.reclassified;
+        self.latency.merge(&other.latency);
+        self.cpu_time.merge(&other.cpu_time);
+        self
+    }
+}
+
+// A snapshot of every executor in a pool. Executors keep counting while it
+// is taken, so the numbers of different executors may be a little apart.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PoolStats {
+    pub executors: Vec<ExecutorSnapshot>,
+}
+
+impl PoolStats {
+    // every executor's counters added up; high-water marks and batch sizes
+    // are the largest of any executor, and `cpu` means nothing.
+    pub fn total(&self) -> ExecutorSnapshot {
+        self.executors
+            .iter()
+            .fold(ExecutorSnapshot::default(), ExecutorSnapshot::merge)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets() {
+        let histogram = CycleHistogram::default();
+        for &cycles in &[0, 1023, 1024, 5000, u64::max_value()] {
+            histogram.record(cycles);
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(5, snapshot.count());
+        assert_eq!(2, snapshot.counts[0]);
+        assert_eq!(1, snapshot.counts[1]);
+        // 5000 is between 2^12 and 2^13
+        assert_eq!(1, snapshot.counts[3]);
+        assert_eq!(Some(8192), snapshot.upper_bound(3));
+        assert_eq!(1, snapshot.counts[HISTOGRAM_BUCKETS - 1]);
+        assert_eq!(u64::max_value(), snapshot.sum);
+        assert_eq!(None, snapshot.upper_bound(HISTOGRAM_BUCKETS - 1));
+    }
+}
@@ -0,0 +1,314 @@
+use super::queue::WorkStealer;
+use rand::prelude::thread_rng;
+use rand::Rng;
+use std::collections::HashMap;
+use std::fs;
+use std::str::FromStr;
+
+// A peer an executor may steal from.
+pub struct Victim {
+    cpu: usize,
+    stealer: WorkStealer,
+}
+
+impl Victim {
+    pub fn new(cpu: usize, stealer: WorkStealer) -> Victim {
+        Victim { cpu, stealer }
+    }
+
+    pub fn get_cpu(&self) -> usize {
+        self.cpu
+    }
+
+    pub fn stealer(&self) -> &WorkStealer {
+        &self.stealer
+    }
+}
+
+// What stealing has amounted to. A steal is a single trip to a victim, which
+// may bring back a whole batch of tasks. Attempts that come back empty handed
+// either lost a race with another thief (`retries`) or found nothing to take
+// (`empties`).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StealStats {
+    pub attempts: usize,
+    pub steals: usize,
+    pub retries: usize,
+    pub empties: usize,
+    pub stolen_tasks: usize,
+    pub largest_batch: usize,
+}
+
+impl StealStats {
+    pub fn mean_batch_size(&self) -> f64 {
+        if self.steals == 0 {
+            0.
+        } else {
+            self.stolen_tasks as f64 / self.steals as f64
+        }
+    }
+
+    pub fn merge(self, other: StealStats) -> StealStats {
+        StealStats {
+            attempts: self.attempts + other.attempts,
+            steals: self.steals + other.steals,
+            retries: self.retries + other.retries,
+            empties: self.empties + other.empties,
+            stolen_tasks: self.stolen_tasks + other.stolen_tasks,
+            largest_batch: self.largest_batch.max(other.largest_batch),
+        }
+    }
+}
+
+// How an idle executor picks who to steal from. Executors steal one lane at
+// a time (deadline work first, then each priority level), and `load` tells
+// how much work a victim holds in the lane being stolen from.
+pub trait StealPolicy: Send {
+    // an index into `victims`, or None to leave this lane alone
+    fn choose_victim(&mut self, thief_cpu: usize, victims: &[Victim], load: &Fn(&Victim) -> usize) -> Option<usize>;
+
+    // how many tasks to take from a victim holding `available` of them;
+    // PoolConfig::max_steal_batch has the last word
+    fn batch_size(&self, _available: usize) -> usize {
+        1
+    }
+}
+
+// The built-in policies, for picking one in a PoolConfig.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StealPolicyKind {
+    Random,
+    RoundRobin,
+    LongestQueue,
+    PowerOfTwoChoices,
+    NearestFirst,
+    StealHalf,
+}
+
+pub const STEAL_POLICIES: [StealPolicyKind; 6] = [
+    StealPolicyKind::Random,
+    StealPolicyKind::RoundRobin,
+    StealPolicyKind::LongestQueue,
+    StealPolicyKind::PowerOfTwoChoices,
+    StealPolicyKind::NearestFirst,
+    StealPolicyKind::StealHalf,
+];
+
+impl StealPolicyKind {
+    // every executor gets a policy of its own
+    pub fn build(&self) -> Box<StealPolicy> {
+        match *self {
+            StealPolicyKind::Random => Box::new(RandomVictim),
+            StealPolicyKind::RoundRobin => Box::new(RoundRobin::new()),
+            StealPolicyKind::LongestQueue => Box::new(LongestQueue),
+            StealPolicyKind::PowerOfTwoChoices => Box::new(PowerOfTwoChoices),
+            StealPolicyKind::NearestFirst => Box::new(NearestFirst::new()),
+            StealPolicyKind::StealHalf => Box::new(StealHalf::new(LongestQueue)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            StealPolicyKind::Random => "random",
+            StealPolicyKind::RoundRobin => "round-robin",
+            StealPolicyKind::LongestQueue => "longest-queue",
+            StealPolicyKind::PowerOfTwoChoices => "power-of-two",
+            StealPolicyKind::NearestFirst => "nearest-first",
+            StealPolicyKind::StealHalf => "steal-half",
+        }
+    }
+}
+
+impl FromStr for StealPolicyKind {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<StealPolicyKind, ()> {
+        STEAL_POLICIES
+            .iter()
+            .find(|kind| kind.name() == name)
+            .cloned()
+            .ok_or(())
+    }
+}
+
+// The victims that have something to give in the lane being stolen from.
+fn loaded(victims: &[Victim], load: &Fn(&Victim) -> usize) -> Vec<usize> {
+    (0..victims.len()).filter(|&i| load(&victims[i]) > 0).collect()
+}
+
+// Picks any victim with work at all; cheap, but blind to how much they have.
+pub struct RandomVictim;
+
+impl StealPolicy for RandomVictim {
+    fn choose_victim(&mut self, _thief_cpu: usize, victims: &[Victim], load: &Fn(&Victim) -> usize) -> Option<usize> {
+        let candidates = loaded(victims, load);
+        if candidates.is_empty() {
+            None
+        } else {
+            Some(candidates[thread_rng().gen_range(0, candidates.len())])
+        }
+    }
+}
+
+// Visits the victims in turn, starting after the last one that had work.
+pub struct RoundRobin {
+    next: usize,
+}
+
+impl RoundRobin {
+    pub fn new() -> RoundRobin {
+        RoundRobin { next: 0 }
+    }
+}
+
+impl StealPolicy for RoundRobin {
+    fn choose_victim(&mut self, _thief_cpu: usize, victims: &[Victim], load: &Fn(&Victim) -> usize) -> Option<usize> {
+        let n_victims = victims.len();
+        let start = self.next;
+        let chosen = (0..n_victims)
+            .map(|offset| (start + offset) % n_victims)
+            .find(|&i| load(&victims[i]) > 0);
+        if let Some(i) = chosen {
+            self.next = i + 1;
+        }
+        chosen
+    }
+}
+
+// Goes after whoever has the most work, at the price of looking at everyone.
+pub struct LongestQueue;
+
+impl StealPolicy for LongestQueue {
+    fn choose_victim(&mut self, _thief_cpu: usize, victims: &[Victim], load: &Fn(&Victim) -> usize) -> Option<usize> {
+        (0..victims.len()).max_by_key(|&i| load(&victims[i]))
+    }
+}
+
+// Looks at two victims with work at random and takes the busier one.
+pub struct PowerOfTwoChoices;
+
+impl StealPolicy for PowerOfTwoChoices {
+    fn choose_victim(&mut self, _thief_cpu: usize, victims: &[Victim], load: &Fn(&Victim) -> usize) -> Option<usize> {
+        let candidates = loaded(victims, load);
+        if candidates.is_empty() {
+            return None;
+        }
+        let mut rng = thread_rng();
+        let first = candidates[rng.gen_range(0, candidates.len())];
+        let second = candidates[rng.gen_range(0, candidates.len())];
+        if load(&victims[first]) >= load(&victims[second]) {
+            Some(first)
+        } else {
+            Some(second)
+        }
+    }
+}
+
+// Where a cpu sits in the machine: its (physical_package_id, core_id), or
+// None where sysfs doesn't say.
+fn cpu_topology(cpu: usize) -> Option<(usize, usize)> {
+    let read = |name: &str| {
+        fs::read_to_string(format!("/sys/devices/system/cpu/cpu{}/topology/{}", cpu, name))
+            .ok()
+            .and_then(|id| id.trim().parse().ok())
+    };
+    match (read("physical_package_id"), read("core_id")) {
+        (Some(package), Some(core)) => Some((package, core)),
+        _ => None,
+    }
+}
+
+// Prefers victims that share a core with the thief, then ones in the same
+// package, going by the topology in sysfs. Cpus sysfs knows nothing about
+// count as being in the thief's package, and ties go to the nearest cpu id.
+pub struct NearestFirst {
+    topology: HashMap<usize, Option<(usize, usize)>>,
+}
+
+impl NearestFirst {
+    pub fn new() -> NearestFirst {
+        NearestFirst { topology: HashMap::new() }
+    }
+
+    fn topology(&mut self, cpu: usize) -> Option<(usize, usize)> {
+        *self.topology.entry(cpu).or_insert_with(|| cpu_topology(cpu))
+    }
+}
+
+impl StealPolicy for NearestFirst {
+    fn choose_victim(&mut self, thief_cpu: usize, victims: &[Victim], load: &Fn(&Victim) -> usize) -> Option<usize> {
+        let thief = self.topology(thief_cpu);
+        let mut nearest = None;
+        for i in loaded(victims, load) {
+            let cpu = victims[i].get_cpu();
+            let level = match (thief, self.topology(cpu)) {
+                (Some(thief), Some(victim)) if thief == victim => 0,
+                (Some((thief_package, _)), Some((package, _))) if thief_package != package => 2,
+                _ => 1,
+            };
+            let distance = (level, if cpu > thief_cpu { cpu - thief_cpu } else { thief_cpu - cpu });
+            if nearest.map_or(true, |(closest, _)| distance < closest) {
+                nearest = Some((distance, i));
+            }
+        }
+        nearest.map(|(_, i)| i)
+    }
+}
+
+// Picks victims the way `P` does, but takes half of their work at once.
+pub struct StealHalf<P: StealPolicy> {
+    policy: P,
+}
+
+impl<P: StealPolicy> StealHalf<P> {
+    pub fn new(policy: P) -> StealHalf<P> {
+        StealHalf { policy }
+    }
+}
+
+impl<P: StealPolicy> StealPolicy for StealHalf<P> {
+    fn choose_victim(&mut self, thief_cpu: usize, victims: &[Victim], load: &Fn(&Victim) -> usize) -> Option<usize> {
+        self.policy.choose_victim(thief_cpu, victims, load)
+    }
+
+    fn batch_size(&self, available: usize) -> usize {
+        (available / 2).max(1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use queue::{QueuePolicy, WorkQueue};
+
+    #[test]
+    fn test_victim_choice() {
+        let queues: Vec<WorkQueue> = (0..3).map(|_| WorkQueue::new(QueuePolicy::Priority, 1)).collect();
+        let victims: Vec<Victim> = queues
+            .iter()
+            .enumerate()
+            .map(|(cpu, queue)| Victim::new(cpu * 4, queue.stealer()))
+            .collect();
+        let loads = [3, 0, 5];
+        let load = |victim: &Victim| loads[victim.get_cpu() / 4];
+
+        assert_eq!(Some(2), LongestQueue.choose_victim(6, &victims, &load));
+        // cpu 4 is closest to 3, but has nothing to give
+        assert_eq!(Some(0), NearestFirst::new().choose_victim(3, &victims, &load));
+        // random picks never land on an empty queue
+        for _ in 0..20 {
+            assert_ne!(Some(1), RandomVictim.choose_victim(0, &victims, &load));
+            assert_ne!(Some(1), PowerOfTwoChoices.choose_victim(0, &victims, &load));
+        }
+        assert_eq!(None, RandomVictim.choose_victim(0, &victims, &|_: &Victim| 0));
+
+        let mut round_robin = RoundRobin::new();
+        let picks: Vec<Option<usize>> = (0..3)
+            .map(|_| round_robin.choose_victim(0, &victims, &load))
+            .collect();
+        assert_eq!(vec![Some(0), Some(2), Some(0)], picks);
+
+        assert_eq!(4, StealHalf::new(LongestQueue).batch_size(9));
+        assert_eq!(Ok(StealPolicyKind::StealHalf), "steal-half".parse());
+    }
+}
@@ -1,7 +1,15 @@
-use super::waiter::{WaitResult, Waiter};
-use cycles::rdtsc;
+use super::cancel::CancelHandle;
+use super::events::SchedulerEvent;
+use super::executor::{current_cpu, on_executor, push_local, report_event, slice_end};
+use super::waiter::{result_channel, Lifecycle, ResultSender, TaskError, WaitResult, Waiter};
+use cycles::{from_seconds, rdtsc};
+use std::any::Any;
+use std::future::Future;
 use std::marker::Send;
-use std::sync::mpsc::{channel, Sender};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::Instant;
 
 pub enum TaskState {
     Unstarted,
@@ -10,68 +18,323 @@ pub enum TaskState {
     Error,
 }
 
+// Executors always tick the most important work they hold first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    High = 0,
+    Normal = 1,
+    Low = 2,
+}
+
+// Why an executor gave up on a task without running it to completion.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AbortReason {
+    Cancelled,
+    TimedOut,
+    PoolShutDown,
+}
+
+impl<E> From<AbortReason> for TaskError<E> {
+    fn from(reason: AbortReason) -> TaskError<E> {
+        match reason {
+            AbortReason::Cancelled => TaskError::Cancelled,
+            AbortReason::TimedOut => TaskError::TimedOut,
+            AbortReason::PoolShutDown => TaskError::PoolShutDown,
+        }
+    }
+}
+
 pub trait Iterable: Send {
     fn tick(&mut self);
     fn get_state(&self) -> &TaskState;
-    fn complete(self: Box<Self>);
+    // hands back any work that was only waiting on this task to finish
+    fn complete(self: Box<Self>) -> Vec<Box<Iterable>>;
     fn mark_stolen(&mut self);
+    fn abort(self: Box<Self>, reason: AbortReason);
+    fn mark_panicked(&mut self, payload: Box<Any + Send>);
+
+    // Everything below is scheduling hints and bookkeeping, which a task
+    // that has no use for them can leave alone.
+
+    fn get_priority(&self) -> Priority {
+        Priority::Normal
+    }
+
+    fn get_deadline(&self) -> Option<u64> {
+        None
+    }
+
+    // cycles a single tick may take, if the task has a budget of its own
+    fn get_time_slice(&self) -> Option<u64> {
+        None
+    }
+
+    fn mark_overrun(&mut self) {}
+
+    fn get_ticks(&self) -> u32 {
+        0
+    }
+
+    fn get_cpu_time(&self) -> u64 {
+        0
+    }
+
+    // rdtsc timestamp of when the task was created, or 0 if it does not say
+    fn get_birthday(&self) -> u64 {
+        0
+    }
+
+    // called when a pool is handed the task, and when an executor queues it
+    // up; only the first of each counts.
+    fn mark_dispatched(&mut self) {}
+
+    fn mark_enqueued(&mut self) {}
+
+    fn is_elephant(&self) -> bool {
+        false
+    }
+
+    // called once an executor decides the task runs too long to stay in
+    // line with short ones
+    fn mark_elephant(&mut self) {}
+
+    fn is_cancelled(&self) -> bool {
+        false
+    }
 }
 
-pub struct Task<F, R>
+// What a task's tick function is when nobody cares about its concrete type,
+// e.g. for keeping different tasks in one collection.
+pub type TickFn<R, E> = Box<FnMut() -> (TaskState, Option<Result<R, E>>) + Send>;
+
+pub struct Task<R, E = (), F = TickFn<R, E>>
 where
-    F: FnMut() -> (TaskState, Option<R>) + Send,
     R: Send,
+    E: Send,
+    F: FnMut() -> (TaskState, Option<Result<R, E>>) + Send,
 {
     // reference to the function, or work this thing needs to do.
     // a way to call poll on that thing. maybe need a Runnable? Why do you need a separate object for the actual function?
     // Poll needs to simply return status, Tick needs to actually advance the thing.
     ticks: u32,
     n_steals: usize,
+    overruns: u32,
     cpu_time: u64,
     birthday: u64,
+    lifecycle: Lifecycle,
     state: TaskState,
-    result: Option<R>,
-    send_result_channel: Option<Sender<WaitResult<R>>>,
+    result: Option<Result<R, E>>,
+    error: Option<TaskError<E>>,
+    send_result_channel: Option<ResultSender<WaitResult<R>, E>>,
+    cancel_handle: Option<CancelHandle>,
+    priority: Priority,
+    // absolute, in rdtsc cycles
+    deadline: Option<u64>,
+    time_slice: Option<u64>,
+    elephant: bool,
     _tick: F,
 }
 
-impl<F, R> Task<F, R>
+impl<R> Task<R>
+where
+    R: Send,
+{
+    pub fn new<F>(mut func: F) -> Task<R, (), impl FnMut() -> (TaskState, Option<Result<R, ()>>) + Send>
+    where
+        F: FnMut() -> (TaskState, Option<R>) + Send,
+    {
+        Task::new_fallible(move || match func() {
+            // an infallible task has nothing to say about why it failed
+            (TaskState::Error, None) => (TaskState::Error, Some(Err(()))),
+            (state, result) => (state, result.map(Ok)),
+        })
+    }
+
+    // Polls `future` once per tick: Pending gives the executor its turn
+    // back, the same way returning TaskState::Incomplete would.
+    pub fn from_future<Fut>(future: Fut) -> Task<R, (), impl FnMut() -> (TaskState, Option<Result<R, ()>>) + Send>
+    where
+        Fut: Future<Output = R> + Send + 'static,
+    {
+        Task::new(poll_each_tick(future))
+    }
+}
+
+impl<R, E, F> Task<R, E, F>
 where
-    F: FnMut() -> (TaskState, Option<R>) + Send,
     R: Send,
+    E: Send,
+    F: FnMut() -> (TaskState, Option<Result<R, E>>) + Send,
 {
-    pub fn new(func: F) -> Task<F, R> {
+    // Like new, but the task may finish with an error of its own, which is
+    // handed to the waiter as TaskError::User.
+    pub fn new_fallible(func: F) -> Task<R, E, F> {
+        let birthday = rdtsc();
         let task = Task {
             _tick: func,
             ticks: 0,
             n_steals: 0,
+            overruns: 0,
             cpu_time: 0,
-            birthday: rdtsc(),
+            birthday,
+            lifecycle: Lifecycle {
+                created: birthday,
+                ..Lifecycle::default()
+            },
             state: TaskState::Unstarted,
             result: None,
+            error: None,
             send_result_channel: None,
+            cancel_handle: None,
+            priority: Priority::Normal,
+            deadline: None,
+            time_slice: None,
+            elephant: false,
         };
         task
     }
 
-    pub fn waiter(&mut self) -> Result<Waiter<WaitResult<R>>, ()> {
+
+    pub fn with_priority(mut self, priority: Priority) -> Task<R, E, F> {
+        self.priority = priority;
+        self
+    }
+
+    // `deadline` is an absolute rdtsc timestamp. Deadlines only change the
+    // order work is ticked in on pools using QueuePolicy::EarliestDeadlineFirst,
+    // but every pool reports whether they were met.
+    pub fn with_deadline(mut self, deadline: u64) -> Task<R, E, F> {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn with_deadline_at(self, deadline: Instant) -> Task<R, E, F> {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let deadline = rdtsc() + from_seconds(remaining.as_secs_f64());
+        self.with_deadline(deadline)
+    }
+
+    // overrides the pool's time slice for this task, in cycles
+    pub fn with_time_slice(mut self, time_slice: u64) -> Task<R, E, F> {
+        self.time_slice = Some(time_slice);
+        self
+    }
+
+    pub fn waiter(&mut self) -> Result<Waiter<WaitResult<R>, E>, ()> {
         match self.send_result_channel {
             Some(_) => Err(()),
             None => {
-                let (sender, receiver) = channel();
+                let (sender, waiter) = result_channel();
                 self.send_result_channel = Some(sender);
-
-                let waiter = Waiter::new(receiver);
                 Ok(waiter)
             }
         }
     }
+
+    pub fn cancel_handle(&mut self) -> CancelHandle {
+        match self.cancel_handle {
+            Some(ref handle) => handle.clone(),
+            None => {
+                let handle = CancelHandle::new();
+                self.cancel_handle = Some(handle.clone());
+                handle
+            }
+        }
+    }
 }
 
-impl<F, R> Iterable for Task<F, R>
+impl<R, E> Task<R, E>
 where
-    F: FnMut() -> (TaskState, Option<R>) + Send,
     R: Send,
+    E: Send,
+{
+    pub fn from_fallible_future<Fut>(
+        future: Fut,
+    ) -> Task<R, E, impl FnMut() -> (TaskState, Option<Result<R, E>>) + Send>
+    where
+        Fut: Future<Output = Result<R, E>> + Send + 'static,
+    {
+        Task::new_fallible(poll_each_tick(future))
+    }
+}
+
+// Tasks are ticked again on their executor's next turn no matter what, so a
+// future polled by a task has nobody to wake.
+struct NoopWaker;
+
+impl Wake for NoopWaker {
+    fn wake(self: Arc<Self>) {}
+}
+
+fn poll_each_tick<Fut, T>(future: Fut) -> impl FnMut() -> (TaskState, Option<T>) + Send
+where
+    Fut: Future<Output = T> + Send + 'static,
+{
+    let mut future = Box::pin(future);
+    let waker = Waker::from(Arc::new(NoopWaker));
+    move || match future.as_mut().poll(&mut Context::from_waker(&waker)) {
+        Poll::Ready(output) => (TaskState::Complete, Some(output)),
+        Poll::Pending => (TaskState::Incomplete, None),
+    }
+}
+
+// Queues `task` on the executor ticking the caller, skipping the dispatcher
+// altogether. Idle peers may still steal it. Hands the task back when called
+// from outside of a task, or when somebody already holds its waiter.
+pub fn spawn<R, E, F>(mut task: Task<R, E, F>) -> Result<Waiter<WaitResult<R>, E>, Task<R, E, F>>
+where
+    R: Send + 'static,
+    E: Send + 'static,
+    F: FnMut() -> (TaskState, Option<Result<R, E>>) + Send + 'static,
+{
+    if !on_executor() {
+        return Err(task);
+    }
+    let waiter = match task.waiter() {
+        Ok(waiter) => waiter,
+        Err(_) => return Err(task),
+    };
+    // cannot fail: we are on an executor thread
+    let _ = push_local(Box::new(task));
+    Ok(waiter)
+}
+
+// Whether the task being ticked on this thread has used up its time slice.
+// Long-running ticks should check this every so often and return
+// TaskState::Incomplete once it is true. Always false outside of a task, or
+// when neither the task nor its pool has a time slice.
+pub fn should_yield() -> bool {
+    rdtsc() > slice_end()
+}
+
+// Lets async code running as a task give up the rest of its tick.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if self.yielded {
+            Poll::Ready(())
+        } else {
+            self.yielded = true;
+            context.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+impl<R, E, F> Iterable for Task<R, E, F>
+where
+    R: Send,
+    E: Send,
+    F: FnMut() -> (TaskState, Option<Result<R, E>>) + Send,
 {
     fn tick(&mut self) {
         self.ticks += 1;
@@ -83,36 +346,127 @@ where
 
         let end = rdtsc();
         self.cpu_time += end - start;
+
+        if self.ticks == 1 {
+            self.lifecycle.first_tick_start = start;
+        }
+        self.lifecycle.last_tick_end = end;
+        if let Some(cpu) = current_cpu() {
+            if self.lifecycle.cpus.last() != Some(&cpu) {
+                self.lifecycle.cpus.push(cpu);
+            }
+        }
     }
 
     fn get_state(&self) -> &TaskState {
         &self.state
     }
 
-    fn complete(self: Box<Self>) {
-        let this = *self;
-        match this.result {
-            Some(result) => match this.send_result_channel {
-                Some(channel) => {
-                    let total_time = rdtsc() - this.birthday;
-                    match channel.send(WaitResult::new(
-                        result,
-                        this.cpu_time,
-                        total_time,
-                        this.ticks,
-                        this.n_steals,
-                    )) {
-                        Ok(_) => (),
-                        Err(_err) => println!("Error sending result: channel failure"),
-                    }
-                }
-                None => println!("Error sending result: channel was None"),
+    fn complete(self: Box<Self>) -> Vec<Box<Iterable>> {
+        let mut this = *self;
+        let outcome = match (this.error, this.result) {
+            (Some(error), _) => Err(error),
+            (None, Some(Ok(result))) => {
+                let now = rdtsc();
+                let deadline_missed = this.deadline.map_or(false, |deadline| now > deadline);
+                this.lifecycle.result_sent = now;
+                Ok(WaitResult::new(
+                    result,
+                    this.cpu_time,
+                    now - this.birthday,
+                    this.ticks,
+                    this.n_steals,
+                    deadline_missed,
+                    this.overruns,
+                    this.lifecycle,
+                ))
+            }
+            (None, Some(Err(error))) => Err(TaskError::User(error)),
+            (None, None) => {
+                report_event(SchedulerEvent::EmptyResult);
+                Err(TaskError::Dropped)
+            }
+        };
+        match this.send_result_channel {
+            Some(channel) => match channel.send(outcome) {
+                Ok(_) => (),
+                Err(_err) => report_event(SchedulerEvent::ResultChannelClosed),
             },
-            None => println!("Error sending result: called complete when result is empty"),
+            None => report_event(SchedulerEvent::MissingResultChannel),
         }
+        vec![]
     }
 
     fn mark_stolen(&mut self) {
         self.n_steals += 1;
     }
+
+    fn get_priority(&self) -> Priority {
+        self.priority
+    }
+
+    fn get_deadline(&self) -> Option<u64> {
+        self.deadline
+    }
+
+    fn get_time_slice(&self) -> Option<u64> {
+        self.time_slice
+    }
+
+    fn mark_overrun(&mut self) {
+        self.overruns += 1;
+    }
+
+    fn get_ticks(&self) -> u32 {
+        self.ticks
+    }
+
+    fn get_cpu_time(&self) -> u64 {
+        self.cpu_time
+    }
+
+    fn get_birthday(&self) -> u64 {
+        self.birthday
+    }
+
+    fn mark_dispatched(&mut self) {
+        if self.lifecycle.dispatched.is_none() {
+            self.lifecycle.dispatched = Some(rdtsc());
+        }
+    }
+
+    fn mark_enqueued(&mut self) {
+        if self.lifecycle.enqueued.is_none() {
+            self.lifecycle.enqueued = Some(rdtsc());
+        }
+    }
+
+    fn is_elephant(&self) -> bool {
+        self.elephant
+    }
+
+    fn mark_elephant(&mut self) {
+        self.elephant = true;
+        self.priority = Priority::Low;
+    }
+
+    fn is_cancelled(&self) -> bool {
+        match self.cancel_handle {
+            Some(ref handle) => handle.is_cancelled(),
+            None => false,
+        }
+    }
+
+    fn abort(self: Box<Self>, reason: AbortReason) {
+        // nobody may be listening anymore, so a failed send is fine here
+        if let Some(channel) = self.send_result_channel {
+            let _ = channel.send(Err(reason.into()));
+        }
+    }
+
+    fn mark_panicked(&mut self, payload: Box<Any + Send>) {
+        self.state = TaskState::Error;
+        self.result = None;
+        self.error = Some(TaskError::Panicked(payload));
+    }
 }
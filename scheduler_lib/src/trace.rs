@@ -0,0 +1,238 @@
+use super::cycles::to_seconds;
+use super::task::Iterable;
+use cycles::rdtsc;
+use std::fmt::Write;
+use std::sync::atomic::{fence, AtomicU64, AtomicUsize, Ordering};
+
+// Tasks are told apart by where they live on the heap, which stays put for
+// as long as the task does. A finished task's id may be handed out again.
+pub(crate) fn task_id(task: &Iterable) -> usize {
+    task as *const Iterable as *const u8 as usize
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceEvent {
+    // the task landed in the executor's queue, from anywhere but a steal
+    Enqueued { task: usize },
+    TickStart { task: usize },
+    TickEnd { task: usize },
+    // recorded by the thief, `to`
+    Stolen { task: usize, from: usize, to: usize },
+    // the task finished, for better or worse
+    Completed { task: usize },
+    Parked,
+    Unparked,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TraceRecord {
+    // rdtsc
+    pub timestamp: u64,
+    pub event: TraceEvent,
+}
+
+// The events one executor recorded, oldest first.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExecutorTrace {
+    pub cpu: usize,
+    pub events: Vec<TraceRecord>,
+}
+
+// An event, taken apart into plain words: what kind it is, then its fields.
+fn encode(event: TraceEvent) -> [usize; 4] {
+    match event {
+        TraceEvent::Enqueued { task } => [0, task, 0, 0],
+        TraceEvent::TickStart { task } => [1, task, 0, 0],
+        TraceEvent::TickEnd { task } => [2, task, 0, 0],
+        TraceEvent::Stolen { task, from, to } => [3, task, from, to],
+        TraceEvent::Completed { task } => [4, task, 0, 0],
+        TraceEvent::Parked => [5, 0, 0, 0],
+        TraceEvent::Unparked => [6, 0, 0, 0],
+    }
+}
+
+fn decode(words: [usize; 4]) -> TraceEvent {
+    let [kind, task, from, to] = words;
+    match kind {
+        0 => TraceEvent::Enqueued { task },
+        1 => TraceEvent::TickStart { task },
+        2 => TraceEvent::TickEnd { task },
+        3 => TraceEvent::Stolen { task, from, to },
+        4 => TraceEvent::Completed { task },
+        5 => TraceEvent::Parked,
+        _ => TraceEvent::Unparked,
+    }
+}
+
+// One place in the ring. `seq` is odd while the slot is being written and
+// 2 * (n + 1) once it holds the n-th event ever recorded.
+#[derive(Default)]
+struct Slot {
+    seq: AtomicUsize,
+    timestamp: AtomicU64,
+    words: [AtomicUsize; 4],
+}
+
+// Keeps the last `capacity` events an executor recorded. Only the executor's
+// own thread records, so recording takes no lock: each slot is a little
+// seqlock, and a dump skips whatever gets overwritten while it reads.
+pub(crate) struct TraceBuffer {
+    slots: Vec<Slot>,
+    // events recorded so far
+    written: AtomicUsize,
+}
+
+impl TraceBuffer {
+    pub fn new(capacity: usize) -> TraceBuffer {
+        TraceBuffer {
+            slots: (0..capacity.max(1)).map(|_| Slot::default()).collect(),
+            written: AtomicUsize::new(0),
+        }
+    }
+
+    // must only ever be called from one thread
+    pub fn record(&self, event: TraceEvent) {
+        let n = self.written.load(Ordering::Relaxed);
+        let slot = &self.slots[n % self.slots.len()];
+        slot.seq.store(2 * n + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        slot.timestamp.store(rdtsc(), Ordering::Relaxed);
+        for (word, value) in slot.words.iter().zip(encode(event).iter()) {
+            word.store(*value, Ordering::Relaxed);
+        }
+        slot.seq.store(2 * n + 2, Ordering::Release);
+        self.written.store(n + 1, Ordering::Release);
+    }
+
+    pub fn events(&self) -> Vec<TraceRecord> {
+        let written = self.written.load(Ordering::Acquire);
+        (written.saturating_sub(self.slots.len())..written)
+            .filter_map(|n| {
+                let slot = &self.slots[n % self.slots.len()];
+                let seq = slot.seq.load(Ordering::Acquire);
+                let timestamp = slot.timestamp.load(Ordering::Relaxed);
+                let mut words = [0; 4];
+                for (value, word) in words.iter_mut().zip(slot.words.iter()) {
+                    *value = word.load(Ordering::Relaxed);
+                }
+                fence(Ordering::Acquire);
+                if seq != 2 * n + 2 || slot.seq.load(Ordering::Relaxed) != seq {
+                    return None;
+                }
+                Some(TraceRecord {
+                    timestamp,
+                    event: decode(words),
+                })
+            })
+            .collect()
+    }
+}
+
+// Renders traces as Chrome trace_event JSON, which chrome://tracing and
+// Perfetto load as one track per executor. Times are in microseconds since
+// the earliest event in any of the traces.
+pub fn chrome_trace(traces: &[ExecutorTrace]) -> String {
+    let start = traces
+        .iter()
+        .filter_map(|trace| trace.events.first())
+        .map(|record| record.timestamp)
+        .min()
+        .unwrap_or(0);
+    let mut events = vec![];
+    for (tid, trace) in traces.iter().enumerate() {
+        events.push(format!(
+            "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":0,\"tid\":{},\"args\":{{\"name\":\"executor {} (cpu {})\"}}}}",
+            tid, tid, trace.cpu
+        ));
+        // the ring may have dropped the start of a tick or a park, whose end
+        // would then close nothing
+        let mut open_ticks = 0;
+        let mut parked = false;
+        for record in &trace.events {
+            let ts = to_seconds(record.timestamp.saturating_sub(start)) * 1e6;
+            let mut event = String::new();
+            let _ = match record.event {
+                TraceEvent::Enqueued { task } => write!(event, "\"name\":\"enqueued\",\"ph\":\"i\",\"s\":\"t\",\"args\":{{\"task\":{}}}", task),
+                TraceEvent::TickStart { task } => {
+                    open_ticks += 1;
+                    write!(event, "\"name\":\"tick\",\"ph\":\"B\",\"args\":{{\"task\":{}}}", task)
+                }
+                TraceEvent::TickEnd { .. } if open_ticks == 0 => continue,
+                TraceEvent::TickEnd { .. } => {
+                    open_ticks -= 1;
+                    write!(event, "\"name\":\"tick\",\"ph\":\"E\"")
+                }
+                TraceEvent::Stolen { task, from, to } => write!(
+                    event,
+                    "\"name\":\"stolen\",\"ph\":\"i\",\"s\":\"t\",\"args\":{{\"task\":{},\"from\":{},\"to\":{}}}",
+                    task, from, to
+                ),
+                TraceEvent::Completed { task } => write!(event, "\"name\":\"completed\",\"ph\":\"i\",\"s\":\"t\",\"args\":{{\"task\":{}}}", task),
+                TraceEvent::Parked => {
+                    parked = true;
+                    write!(event, "\"name\":\"parked\",\"ph\":\"B\"")
+                }
+                TraceEvent::Unparked if !parked => continue,
+                TraceEvent::Unparked => {
+                    parked = false;
+                    write!(event, "\"name\":\"parked\",\"ph\":\"E\"")
+                }
+            };
+            events.push(format!("{{{},\"ts\":{:.3},\"pid\":0,\"tid\":{}}}", event, ts, tid));
+        }
+    }
+    format!("{{\"traceEvents\":[{}],\"displayTimeUnit\":\"ns\"}}", events.join(",\n"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ring_keeps_latest() {
+        let buffer = TraceBuffer::new(2);
+        for task in 0..3 {
+            buffer.record(TraceEvent::Enqueued { task });
+        }
+        let events: Vec<TraceEvent> = buffer.events().iter().map(|record| record.event).collect();
+        assert_eq!(vec![TraceEvent::Enqueued { task: 1 }, TraceEvent::Enqueued { task: 2 }], events);
+
+        let stolen = TraceEvent::Stolen { task: 3, from: 4, to: 5 };
+        buffer.record(stolen);
+        buffer.record(TraceEvent::Parked);
+        let events: Vec<TraceEvent> = buffer.events().iter().map(|record| record.event).collect();
+        assert_eq!(vec![stolen, TraceEvent::Parked], events);
+    }
+
+    #[test]
+    fn test_chrome_trace() {
+        let record = |timestamp, event| TraceRecord { timestamp, event };
+        let traces = vec![
+            ExecutorTrace {
+                cpu: 2,
+                events: vec![
+                    // the start of this tick fell out of the ring
+                    record(100, TraceEvent::TickEnd { task: 7 }),
+                    record(200, TraceEvent::TickStart { task: 8 }),
+                    record(300, TraceEvent::TickEnd { task: 8 }),
+                    record(400, TraceEvent::Completed { task: 8 }),
+                ],
+            },
+            ExecutorTrace {
+                cpu: 5,
+                events: vec![
+                    record(150, TraceEvent::Stolen { task: 9, from: 2, to: 5 }),
+                    record(250, TraceEvent::Parked),
+                ],
+            },
+        ];
+        let json = chrome_trace(&traces);
+        assert!(json.starts_with("{\"traceEvents\":["));
+        assert!(json.contains("\"args\":{\"name\":\"executor 1 (cpu 5)\"}"));
+        assert_eq!(1, json.matches("\"ph\":\"E\"").count());
+        assert_eq!(2, json.matches("\"ph\":\"B\"").count());
+        assert!(json.contains("\"name\":\"stolen\",\"ph\":\"i\",\"s\":\"t\",\"args\":{\"task\":9,\"from\":2,\"to\":5}"));
+        assert_eq!(2, json.matches("\"tid\":1}").count());
+        assert_eq!(json.matches('{').count(), json.matches('}').count());
+    }
+}
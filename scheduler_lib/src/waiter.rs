@@ -1,30 +1,346 @@
-use std::sync::mpsc::Receiver;
+use std::any::Any;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, SendError, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
 
-pub struct Waiter<T>
+#[derive(Debug)]
+pub enum TaskError<E = ()> {
+    // the task itself reported a failure
+    User(E),
+    // the task panicked while ticking; holds the panic payload
+    Panicked(Box<Any + Send>),
+    // the task was withdrawn before it could complete
+    Cancelled,
+    // the pool gave up waiting for the task while shutting down
+    TimedOut,
+    // the pool was shut down before the task could complete
+    PoolShutDown,
+    // the task went away without reporting a result
+    Dropped,
+}
+
+// Why a bounded wait on a Waiter came back without a result.
+#[derive(Debug)]
+pub enum WaitError<E = ()> {
+    // the task has not reported back yet
+    NotReady,
+    // the task did not report back before the timeout or deadline
+    TimedOut,
+    // the task reported back, but it failed, or went away without reporting
+    // back at all (TaskError::Dropped)
+    Task(TaskError<E>),
+}
+
+// Shared between a ResultSender and its Waiter so that whoever is waiting
+// on the result (a blocked thread or an async task) gets woken when it lands.
+struct Signal {
+    listener: Mutex<Option<Waker>>,
+}
+
+impl Signal {
+    fn listen(&self, listener: Option<Waker>) {
+        *self.listener.lock().unwrap() = listener;
+    }
+
+    fn notify(&self) {
+        if let Some(ref listener) = *self.listener.lock().unwrap() {
+            listener.wake_by_ref();
+        }
+    }
+}
+
+// Lets a blocked thread listen for results like an async task would.
+pub(crate) struct ThreadWaker(pub(crate) Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+pub fn result_channel<T, E>() -> (ResultSender<T, E>, Waiter<T, E>)
 where
     T: Send,
+    E: Send,
 {
-    receive_result_channel: Receiver<T>,
+    let (sender, receiver) = channel();
+    let signal = Arc::new(Signal {
+        listener: Mutex::new(None),
+    });
+    let result_sender = ResultSender {
+        send_result_channel: Some(sender),
+        signal: signal.clone(),
+    };
+    let waiter = Waiter {
+        receive_result_channel: receiver,
+        signal,
+    };
+    (result_sender, waiter)
 }
 
-impl<T> Waiter<T>
+// The task's end of a Waiter. Listeners are woken when a result is sent,
+// and also when the sender is dropped without sending one.
+pub struct ResultSender<T, E = ()>
 where
     T: Send,
+    E: Send,
 {
-    pub fn new(channel: Receiver<T>) -> Waiter<T> {
-        Waiter {
-            receive_result_channel: channel,
-        }
+    // only None while the sender is being dropped
+    send_result_channel: Option<Sender<Result<T, TaskError<E>>>>,
+    signal: Arc<Signal>,
+}
+
+impl<T, E> ResultSender<T, E>
+where
+    T: Send,
+    E: Send,
+{
+    pub fn send(
+        &self,
+        result: Result<T, TaskError<E>>,
+    ) -> Result<(), SendError<Result<T, TaskError<E>>>> {
+        let sent = match self.send_result_channel {
+            Some(ref channel) => channel.send(result),
+            None => Err(SendError(result)),
+        };
+        self.signal.notify();
+        sent
+    }
+}
+
+impl<T, E> Drop for ResultSender<T, E>
+where
+    T: Send,
+    E: Send,
+{
+    fn drop(&mut self) {
+        // disconnect first, so that whoever gets woken sees the task is gone
+        self.send_result_channel.take();
+        self.signal.notify();
     }
+}
 
-    pub fn await(&self) -> Result<T, ()> {
+pub struct Waiter<T, E = ()>
+where
+    T: Send,
+    E: Send,
+{
+    receive_result_channel: Receiver<Result<T, TaskError<E>>>,
+    signal: Arc<Signal>,
+}
+
+impl<T, E> Waiter<T, E>
+where
+    T: Send,
+    E: Send,
+{
+    pub fn await(&self) -> Result<T, TaskError<E>> {
         match self.receive_result_channel.recv() {
-            Ok(result) => Ok(result),
-            Err(_err) => Err(()),
+            Ok(result) => result,
+            Err(_err) => Err(TaskError::Dropped),
+        }
+    }
+
+    pub fn try_await(&self) -> Result<T, WaitError<E>> {
+        match self.receive_result_channel.try_recv() {
+            Ok(result) => result.map_err(WaitError::Task),
+            Err(TryRecvError::Empty) => Err(WaitError::NotReady),
+            Err(TryRecvError::Disconnected) => Err(WaitError::Task(TaskError::Dropped)),
+        }
+    }
+
+    pub fn await_timeout(&self, timeout: Duration) -> Result<T, WaitError<E>> {
+        match self.receive_result_channel.recv_timeout(timeout) {
+            Ok(result) => result.map_err(WaitError::Task),
+            Err(RecvTimeoutError::Timeout) => Err(WaitError::TimedOut),
+            Err(RecvTimeoutError::Disconnected) => Err(WaitError::Task(TaskError::Dropped)),
+        }
+    }
+
+    pub fn await_deadline(&self, deadline: Instant) -> Result<T, WaitError<E>> {
+        let now = Instant::now();
+        if deadline > now {
+            self.await_timeout(deadline - now)
+        } else {
+            // the deadline has passed, but a result that is already here
+            // still counts
+            match self.try_await() {
+                Err(WaitError::NotReady) => Err(WaitError::TimedOut),
+                other => other,
+            }
+        }
+    }
+
+    // like try_await, but keeps waiting as long as the result is not ready
+    fn ready_result(&self) -> Option<Result<T, TaskError<E>>> {
+        match self.try_await() {
+            Ok(result) => Some(Ok(result)),
+            Err(WaitError::NotReady) => None,
+            Err(WaitError::TimedOut) => None,
+            Err(WaitError::Task(error)) => Some(Err(error)),
+        }
+    }
+}
+
+impl<T, E> Future for Waiter<T, E>
+where
+    T: Send,
+    E: Send,
+{
+    type Output = Result<T, TaskError<E>>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        if let Some(result) = self.ready_result() {
+            return Poll::Ready(result);
+        }
+
+        // register before giving up, then look once more: a result sent in
+        // between would not have known whom to wake.
+        self.signal.listen(Some(context.waker().clone()));
+        match self.ready_result() {
+            Some(result) => Poll::Ready(result),
+            None => Poll::Pending,
         }
     }
 }
 
+// Waits for every waiter, returning their results in the same order.
+pub fn wait_all<T, E>(waiters: Vec<Waiter<T, E>>) -> Vec<Result<T, TaskError<E>>>
+where
+    T: Send,
+    E: Send,
+{
+    waiters.iter().map(|waiter| waiter.await()).collect()
+}
+
+// Waits for the first of `waiters` to finish, returning its index and
+// result, or None if there is nothing to wait for. Each waiter only yields
+// one result: one that already handed out its result reports Dropped.
+pub fn wait_any<T, E>(waiters: &[Waiter<T, E>]) -> Option<(usize, Result<T, TaskError<E>>)>
+where
+    T: Send,
+    E: Send,
+{
+    if waiters.is_empty() {
+        return None;
+    }
+
+    let mut listening = false;
+    loop {
+        let ready = waiters
+            .iter()
+            .enumerate()
+            .filter_map(|(i, waiter)| waiter.ready_result().map(|result| (i, result)))
+            .next();
+        if ready.is_some() {
+            if listening {
+                waiters.iter().for_each(|waiter| waiter.signal.listen(None));
+            }
+            return ready;
+        }
+
+        // register before sleeping, then look once more: anything that
+        // finished in between would not have known to wake us.
+        if listening {
+            thread::park();
+        } else {
+            let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+            waiters
+                .iter()
+                .for_each(|waiter| waiter.signal.listen(Some(waker.clone())));
+            listening = true;
+        }
+    }
+}
+
+// Yields the results of `waiters` in the order they finish, along with each
+// waiter's index in the original vector.
+pub fn completions<T, E>(waiters: Vec<Waiter<T, E>>) -> Completions<T, E>
+where
+    T: Send,
+    E: Send,
+{
+    // every waiter says which one it is when its result lands, so finding
+    // the next result never means looking at all of them
+    let (send_ready, ready) = channel();
+    for (index, waiter) in waiters.iter().enumerate() {
+        let waker = Waker::from(Arc::new(ReadyWaker {
+            index,
+            ready: send_ready.clone(),
+        }));
+        waiter.signal.listen(Some(waker));
+        // results sent before we were listening woke nobody
+        let _ = send_ready.send(index);
+    }
+    let done = vec![false; waiters.len()];
+    Completions {
+        remaining: waiters.len(),
+        waiters,
+        done,
+        ready,
+        _send_ready: send_ready,
+    }
+}
+
+struct ReadyWaker {
+    index: usize,
+    ready: Sender<usize>,
+}
+
+impl Wake for ReadyWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        // nobody may be listening anymore, which is fine
+        let _ = self.ready.send(self.index);
+    }
+}
+
+pub struct Completions<T, E = ()>
+where
+    T: Send,
+    E: Send,
+{
+    waiters: Vec<Waiter<T, E>>,
+    // waiters that already handed out their result
+    done: Vec<bool>,
+    remaining: usize,
+    // indices of waiters that may have a result. A waiter can show up more
+    // than once, or before its result is in, which next() skips over.
+    ready: Receiver<usize>,
+    // keeps `ready` from ever disconnecting
+    _send_ready: Sender<usize>,
+}
+
+impl<T, E> Iterator for Completions<T, E>
+where
+    T: Send,
+    E: Send,
+{
+    type Item = (usize, Result<T, TaskError<E>>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.remaining > 0 {
+            let index = self.ready.recv().unwrap();
+            if self.done[index] {
+                continue;
+            }
+            if let Some(result) = self.waiters[index].ready_result() {
+                self.done[index] = true;
+                self.remaining -= 1;
+                return Some((index, result));
+            }
+        }
+        None
+    }
+}
+
 pub struct WaitResult<T>
 where
     T: Send,
@@ -34,6 +350,9 @@ where
     total_time: u64,
     ticks: u32,
     n_steals: usize,
+    deadline_missed: bool,
+    overruns: u32,
+    lifecycle: Lifecycle,
 }
 
 impl<T> WaitResult<T>
@@ -46,6 +365,9 @@ where
         total_time: u64,
         ticks: u32,
         n_steals: usize,
+        deadline_missed: bool,
+        overruns: u32,
+        lifecycle: Lifecycle,
     ) -> WaitResult<T> {
         WaitResult {
             result,
@@ -53,9 +375,20 @@ where
             total_time,
             ticks,
             n_steals,
+            deadline_missed,
+            overruns,
+            lifecycle,
         }
     }
 
+    pub fn get_result(&self) -> &T {
+        &self.result
+    }
+
+    pub fn into_result(self) -> T {
+        self.result
+    }
+
     pub fn get_cpu_time(&self) -> u64 {
         self.cpu_time
     }
@@ -71,4 +404,142 @@ where
     pub fn get_n_steals(&self) -> usize {
         self.n_steals
     }
+
+    // false for tasks that never had a deadline
+    pub fn get_deadline_missed(&self) -> bool {
+        self.deadline_missed
+    }
+
+    // number of ticks that ran past their time slice
+    pub fn get_overruns(&self) -> u32 {
+        self.overruns
+    }
+
+    pub fn get_lifecycle(&self) -> &Lifecycle {
+        &self.lifecycle
+    }
+}
+
+// When a task went through each step on its way to its waiter, as rdtsc
+// timestamps, and the cpus it was ticked on.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Lifecycle {
+    pub created: u64,
+    // handed to a pool; None for tasks that never went through one, like
+    // those spawned from inside another task
+    pub dispatched: Option<u64>,
+    // first landed in an executor's queue; None for tasks run in place
+    pub enqueued: Option<u64>,
+    pub first_tick_start: u64,
+    pub last_tick_end: u64,
+    pub result_sent: u64,
+    // in the order the task ran on them, each cpu listed again whenever the
+    // task came back to it from another
+    pub cpus: Vec<usize>,
+}
+
+impl Lifecycle {
+    // from being handed to the pool to landing in an executor's queue
+    pub fn dispatch_delay(&self) -> Option<u64> {
+        match (self.dispatched, self.enqueued) {
+            (Some(dispatched), Some(enqueued)) => Some(enqueued.saturating_sub(dispatched)),
+            _ => None,
+        }
+    }
+
+    // from landing in an executor's queue to first being ticked
+    pub fn queueing_delay(&self) -> Option<u64> {
+        self.enqueued
+            .map(|enqueued| self.first_tick_start.saturating_sub(enqueued))
+    }
+
+    // from the first tick to the end of the last one, including whatever
+    // time the task spent queued up again in between
+    pub fn service_time(&self) -> u64 {
+        self.last_tick_end.saturating_sub(self.first_tick_start)
+    }
+
+    // how many times the task moved to another cpu between ticks
+    pub fn migrations(&self) -> usize {
+        self.cpus.len().saturating_sub(1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bounded_waits() {
+        let (sender, waiter) = result_channel::<usize, ()>();
+        match waiter.try_await() {
+            Err(WaitError::NotReady) => {}
+            _ => panic!("expected no result yet"),
+        }
+        match waiter.await_timeout(Duration::from_millis(1)) {
+            Err(WaitError::TimedOut) => {}
+            _ => panic!("expected the wait to time out"),
+        }
+        match waiter.await_deadline(Instant::now()) {
+            Err(WaitError::TimedOut) => {}
+            _ => panic!("expected the wait to time out"),
+        }
+
+        sender.send(Ok(7)).unwrap();
+        assert_eq!(7, waiter.await_deadline(Instant::now()).unwrap());
+        sender.send(Err(TaskError::Cancelled)).unwrap();
+        match waiter.try_await() {
+            Err(WaitError::Task(TaskError::Cancelled)) => {}
+            _ => panic!("expected the task error"),
+        }
+
+        drop(sender);
+        match waiter.await_timeout(Duration::from_millis(1)) {
+            Err(WaitError::Task(TaskError::Dropped)) => {}
+            _ => panic!("expected the task to be dropped"),
+        }
+    }
+
+    #[test]
+    fn test_completion_order() {
+        let channels: Vec<(ResultSender<usize>, Waiter<usize>)> =
+            (0..3).map(|_| result_channel()).collect();
+        let (senders, waiters): (Vec<_>, Vec<_>) = channels.into_iter().unzip();
+
+        let finisher = thread::spawn(move || {
+            for (i, sender) in senders.into_iter().enumerate().rev() {
+                thread::sleep(Duration::from_millis(5));
+                sender.send(Ok(i)).unwrap();
+            }
+        });
+
+        let order: Vec<usize> = completions(waiters)
+            .map(|(i, result)| {
+                assert_eq!(i, result.unwrap());
+                i
+            })
+            .collect();
+        assert_eq!(vec![2, 1, 0], order);
+        finisher.join().unwrap();
+    }
+
+    #[test]
+    fn test_completions_of_dropped_tasks() {
+        let (sender, waiter) = result_channel::<usize, ()>();
+        let (early, early_waiter) = result_channel::<usize, ()>();
+        early.send(Ok(1)).unwrap();
+        let dropper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(5));
+            drop(sender);
+        });
+
+        let mut results = completions(vec![waiter, early_waiter]);
+        assert_eq!(1, results.next().unwrap().1.unwrap());
+        match results.next() {
+            Some((0, Err(TaskError::Dropped))) => {}
+            _ => panic!("expected the task to be dropped"),
+        }
+        assert!(results.next().is_none());
+        dropper.join().unwrap();
+    }
 }